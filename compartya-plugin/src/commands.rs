@@ -1,9 +1,19 @@
+use compartya_shared::{sanitize_name, Order};
 use rrplug::{
     bindings::cvar::convar::FCVAR_CLIENTDLL, mid::engine::concommands::find_concommand, prelude::*,
 };
 use std::sync::OnceLock;
 
-use crate::{bindings::ENGINE_FUNCTIONS, exports::PLUGIN, LocalMessage};
+use crate::{
+    bindings::ENGINE_FUNCTIONS,
+    exports::PLUGIN,
+    launch_args::{parse_host_addr, to_password},
+    matchmaking::split_lobby_secret,
+    orders::server_password_for,
+    server_ranking::ServerCriteria,
+    settings::{self, Settings, CONVARS},
+    LocalMessage, ServerPassword,
+};
 
 static ORIGINAL_DISCONNECT: OnceLock<
     unsafe extern "C" fn(*const rrplug::bindings::cvar::command::CCommand),
@@ -29,7 +39,23 @@ pub fn hook_disconnect() {
     }
 }
 
-pub fn create_commands(engine_data: &EngineData, token: EngineToken) {
+pub fn create_commands(engine_data: &EngineData, token: EngineToken, settings: &Settings) {
+    for (name, help) in CONVARS {
+        let register = ConVarRegister {
+            callback: Some(settings_changed),
+            ..ConVarRegister::new(
+                name,
+                settings.get(name).unwrap_or_default(),
+                FCVAR_CLIENTDLL as i32,
+                help,
+            )
+        };
+
+        if let Err(err) = ConVarStruct::try_new(&register, token) {
+            log::error!("failed to create the {name} convar: {err}");
+        }
+    }
+
     engine_data
         .register_concommand(
             "p_host_lobby",
@@ -40,11 +66,21 @@ pub fn create_commands(engine_data: &EngineData, token: EngineToken) {
         )
         .expect("failed to create host_lobby command");
 
+    engine_data
+        .register_concommand(
+            "p_connect_direct",
+            connect_direct,
+            "command to connect to a host without the matchmaking server: p_connect_direct <ip:port> <password;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create connect_direct command");
+
     engine_data
         .register_concommand(
             "p_connect_to_lobby",
             connect_to_lobby,
-            "command to connect to a lobby: p_connect_to_lobby <lobby_id> <password;optional>",
+            "command to connect to a lobby: p_connect_to_lobby <lobby_id or lobby_id@server> <password;optional>",
             FCVAR_CLIENTDLL as i32,
             token,
         )
@@ -70,6 +106,166 @@ pub fn create_commands(engine_data: &EngineData, token: EngineToken) {
         )
         .expect("failed to create leave command");
 
+    engine_data
+        .register_concommand(
+            "p_say",
+            say,
+            "command to send a message to the party chat: p_say <message>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create say command");
+
+    engine_data
+        .register_concommand(
+            "p_nickname",
+            nickname,
+            "command to set a nickname shown to the party next time you join: p_nickname <nickname;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create nickname command");
+
+    engine_data
+        .register_concommand(
+            "p_members",
+            members,
+            "command to list the party members: p_members",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create members command");
+
+    engine_data
+        .register_concommand(
+            "p_ready_check",
+            ready_check,
+            "command to ask the party if everyone is ready: p_ready_check",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create ready_check command");
+
+    engine_data
+        .register_concommand(
+            "p_ready",
+            ready,
+            "command to answer a ready check: p_ready <0/1>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create ready command");
+
+    engine_data
+        .register_concommand(
+            "p_kick",
+            kick,
+            "command to kick a member from your party: p_kick <uid or name> <reason;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create kick command");
+
+    engine_data
+        .register_concommand(
+            "p_ban",
+            ban,
+            "command to kick a member and keep them out until the lobby closes: p_ban <uid or name> <reason;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create ban command");
+
+    engine_data
+        .register_concommand(
+            "p_party_size",
+            party_size,
+            "command to limit how many players can be in your party, you included: p_party_size <2-255>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create party_size command");
+
+    engine_data
+        .register_concommand(
+            "p_lock",
+            lock,
+            "command to stop new members from joining your party: p_lock <0/1>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create lock command");
+
+    engine_data
+        .register_concommand(
+            "p_server_password",
+            server_password,
+            "command to set the password of the server you are on so the party can follow: p_server_password <password;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create server_password command");
+
+    engine_data
+        .register_concommand(
+            "p_check_mods",
+            check_mods,
+            "command to check if everyone in the party has the mods the current server needs: p_check_mods",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create check_mods command");
+
+    engine_data
+        .register_concommand(
+            "p_send_order",
+            send_order,
+            "command to send the order the mod check is holding back: p_send_order",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create send_order command");
+
+    engine_data
+        .register_concommand(
+            "p_revoke_invite",
+            revoke_invite,
+            "command to stop the current party link from working and make a new one: p_revoke_invite",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create revoke_invite command");
+
+    engine_data
+        .register_concommand(
+            "p_nat_check",
+            nat_check,
+            "command to check what kind of nat is between you and the matchmaking server: p_nat_check",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create nat_check command");
+
+    engine_data
+        .register_concommand(
+            "p_join_first",
+            join_first,
+            "command to let members join before everyone else when a server is almost full: p_join_first <uid or name> ...",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create join_first command");
+
+    engine_data
+        .register_concommand(
+            "p_find_server",
+            find_server,
+            "command to move the party to the best server with room for everyone: p_find_server <playlist=;optional> <map=;optional> <region=;optional> <mods=a,b;optional>",
+            FCVAR_CLIENTDLL as i32,
+            token,
+        )
+        .expect("failed to create find_server command");
+
     engine_data
         .register_concommand(
             "p_test_connect",
@@ -81,22 +277,50 @@ pub fn create_commands(engine_data: &EngineData, token: EngineToken) {
         .expect("failed to create leave command");
 }
 
+/// every settings convar lands here; they are read back together and saved if anything changed
+#[rrplug::convar]
+fn settings_changed() {
+    let plugin = PLUGIN.wait();
+    let mut changed = plugin.settings.lock().clone();
+
+    for (name, _) in CONVARS {
+        let Ok(convar) = ConVarStruct::find_convar_by_name(name, engine_token) else {
+            continue;
+        };
+        let value = convar.get_value_string();
+
+        if !changed.set(name, &value) {
+            log::warn!("{value:?} isn't a valid value for {name}");
+        }
+    }
+
+    if *plugin.settings.lock() == changed {
+        return;
+    }
+
+    settings::save(&changed);
+    *plugin.settings.lock() = changed.clone();
+
+    if let Err(err) = plugin
+        .send_runframe
+        .send(LocalMessage::SettingsChanged(changed))
+    {
+        log::info!("failed to apply the settings {err}")
+    }
+}
+
 #[rrplug::concommand]
 fn host_lobby(cmd: CCommandResult) -> Option<()> {
-    let mut password = cmd
-        .get_arg(0)
-        .map(|s| s.to_string())
-        .unwrap_or_default()
-        .chars()
-        .collect::<Vec<char>>();
-    password.resize(8, ' ');
-
-    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::BecomeHost(
-        password
-            .try_into()
-            .map_err(|_| log::info!("the password must be 8 chars in lenght"))
-            .ok()?,
-    )) {
+    let Some(password) = to_password(cmd.get_arg(0).unwrap_or_default()) else {
+        log::error!("the password can't be longer than 8 chars");
+        return None;
+    };
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::BecomeHost(password))
+    {
         log::info!("failed to create a new lobby {err}")
     }
 
@@ -126,17 +350,14 @@ fn connect_to_lobby(cmd: CCommandResult) -> Option<()> {
         return None;
     }
 
-    let Some(lobby_id) = cmd.get_arg(0).map(|s| s.to_string()) else {
+    let Some((lobby_id, server)) = cmd.get_arg(0).map(split_lobby_secret) else {
         log::warn!("the lobby id must be 8 chars in lenght");
         return None;
     };
-    let mut password = cmd
-        .get_arg(1)
-        .map(|s| s.to_string())
-        .unwrap_or_default()
-        .chars()
-        .collect::<Vec<char>>();
-    password.resize(8, ' ');
+    let Some(password) = to_password(cmd.get_arg(1).unwrap_or_default()) else {
+        log::error!("the password can't be longer than 8 chars");
+        return None;
+    };
 
     let send_runframe = &PLUGIN.wait().send_runframe;
 
@@ -151,10 +372,8 @@ fn connect_to_lobby(cmd: CCommandResult) -> Option<()> {
             .try_into()
             .map_err(|_| log::info!("the lobby id must be 8 chars in lenght"))
             .ok()?,
-        password
-            .try_into()
-            .map_err(|_| log::info!("the password must be 8 chars in lenght"))
-            .ok()?,
+        password,
+        server,
     )) {
         log::info!("failed to send connection message {err}")
     }
@@ -163,16 +382,50 @@ fn connect_to_lobby(cmd: CCommandResult) -> Option<()> {
 }
 
 #[rrplug::concommand]
-fn order_to_this_server() {
-    _ = PLUGIN.wait().send_runframe.send(LocalMessage::NewOrder(
-        compartya_shared::Order::JoinServer(
-            ConVarStruct::find_convar_by_name("ns_server_name", engine_token)
-                .ok()
-                .map(|cvar| cvar.get_value_string())
-                .unwrap_or_default(),
-            "".into(),
-        ),
-    ));
+fn connect_direct(cmd: CCommandResult) -> Option<()> {
+    let Some(addr) = cmd.get_arg(0).and_then(parse_host_addr) else {
+        log::warn!("give the host's address as ip:port");
+        return None;
+    };
+    let Some(password) = to_password(cmd.get_arg(1).unwrap_or_default()) else {
+        log::error!("the password can't be longer than 8 chars");
+        return None;
+    };
+
+    let send_runframe = &PLUGIN.wait().send_runframe;
+
+    if let Err(err) = send_runframe.send(LocalMessage::Leave) {
+        log::info!("failed to send downgrade {err}")
+    }
+
+    if let Err(err) = send_runframe.send(LocalMessage::ConnectDirect(addr, None, password)) {
+        log::info!("failed to send connection message {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn order_to_this_server() -> Option<()> {
+    let Some(mut order) = PLUGIN.wait().joined_server.lock().clone() else {
+        log::error!("compartya didn't see you join this server; rejoin it from the server browser");
+        return None;
+    };
+
+    // p_server_password may have been used after we joined
+    if let Order::JoinServer { id, password, .. } = &mut order {
+        *password = server_password_for(id).unwrap_or_default();
+    }
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::StageOrder(order))
+    {
+        log::info!("failed to send the order {err}")
+    }
+
+    None
 }
 
 #[rrplug::concommand]
@@ -184,6 +437,262 @@ fn leave() -> Option<()> {
     None
 }
 
+#[rrplug::concommand]
+fn say(cmd: CCommandResult) -> Option<()> {
+    let text = cmd.get_args().join(" ");
+
+    if text.trim().is_empty() {
+        log::warn!("usage: p_say <message>");
+        return None;
+    }
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::SendChat(text))
+    {
+        log::info!("failed to send chat message {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn nickname(cmd: CCommandResult) -> Option<()> {
+    let nickname = sanitize_name(&cmd.get_args().join(" "));
+
+    match nickname.as_ref() {
+        Some(nickname) => log::info!("nickname set to {nickname}"),
+        None => log::info!("nickname cleared"),
+    }
+
+    PLUGIN.wait().profile.lock().nickname = nickname;
+
+    None
+}
+
+#[rrplug::concommand]
+fn members() -> Option<()> {
+    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::ListMembers) {
+        log::info!("failed to list members {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn ready_check() -> Option<()> {
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::StartReadyCheck)
+    {
+        log::info!("failed to start a ready check {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn ready(cmd: CCommandResult) -> Option<()> {
+    let ready = match cmd.get_arg(0) {
+        Some("1") | Some("true") | None => true,
+        Some("0") | Some("false") => false,
+        Some(_) => {
+            log::warn!("usage: p_ready <0/1>");
+            return None;
+        }
+    };
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::AnswerReady(ready))
+    {
+        log::info!("failed to answer the ready check {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn kick(cmd: CCommandResult) -> Option<()> {
+    send_kick(cmd, false)
+}
+
+#[rrplug::concommand]
+fn ban(cmd: CCommandResult) -> Option<()> {
+    send_kick(cmd, true)
+}
+
+fn send_kick(cmd: CCommandResult, ban: bool) -> Option<()> {
+    let Some(member) = cmd.get_arg(0).map(|s| s.to_string()) else {
+        log::warn!("you must provide the uid or name of the member");
+        return None;
+    };
+    let reason = cmd.get_args().get(1..).unwrap_or_default().join(" ");
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::Kick(member, reason, ban))
+    {
+        log::info!("failed to kick {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn party_size(cmd: CCommandResult) -> Option<()> {
+    let Some(max_size) = cmd.get_arg(0).and_then(|arg| arg.parse::<u8>().ok()) else {
+        log::warn!("usage: p_party_size <2-255>");
+        return None;
+    };
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::SetPartySize(max_size))
+    {
+        log::info!("failed to set the party size {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn lock(cmd: CCommandResult) -> Option<()> {
+    let locked = match cmd.get_arg(0) {
+        Some("1") | Some("true") | None => true,
+        Some("0") | Some("false") => false,
+        Some(_) => {
+            log::warn!("usage: p_lock <0/1>");
+            return None;
+        }
+    };
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::LockLobby(locked))
+    {
+        log::info!("failed to lock the lobby {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn server_password(cmd: CCommandResult) -> Option<()> {
+    let password = cmd.get_args().join(" ");
+
+    if password.is_empty() {
+        log::info!("server password cleared");
+    } else {
+        log::info!("server password set; it will be sent with the next order");
+    }
+
+    *PLUGIN.wait().server_password.lock() = ServerPassword::new(password);
+
+    None
+}
+
+#[rrplug::concommand]
+fn check_mods() -> Option<()> {
+    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::CheckMods) {
+        log::info!("failed to check mods {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn send_order() -> Option<()> {
+    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::ConfirmOrder) {
+        log::info!("failed to send order {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn revoke_invite() -> Option<()> {
+    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::RevokeInvite) {
+        log::info!("failed to revoke the invite {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn nat_check() -> Option<()> {
+    if let Err(err) = PLUGIN.wait().send_runframe.send(LocalMessage::CheckNat) {
+        log::info!("failed to check the nat {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn join_first(cmd: CCommandResult) -> Option<()> {
+    let members = cmd
+        .get_args()
+        .iter()
+        .map(|member| member.to_string())
+        .collect::<Vec<_>>();
+
+    if members.is_empty() {
+        log::warn!("usage: p_join_first <uid or name> ...");
+        return None;
+    }
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::PrioritizeJoin(members))
+    {
+        log::info!("failed to change the join order {err}")
+    }
+
+    None
+}
+
+#[rrplug::concommand]
+fn find_server(cmd: CCommandResult) -> Option<()> {
+    let mut criteria = ServerCriteria::default();
+
+    for arg in cmd.get_args() {
+        match arg.split_once('=') {
+            Some(("playlist", playlist)) => criteria.playlist = Some(playlist.to_string()),
+            Some(("map", map)) => criteria.map = Some(map.to_string()),
+            Some(("region", region)) => criteria.region = Some(region.to_string()),
+            Some(("mods", mods)) => {
+                criteria.allowed_mods = Some(
+                    mods.split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(|name| name.to_string())
+                        .collect(),
+                )
+            }
+            _ => {
+                log::warn!("unknown filter {arg}; use playlist=, map=, region= or mods=");
+                return None;
+            }
+        }
+    }
+
+    if let Err(err) = PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::FindServer(criteria))
+    {
+        log::info!("failed to look for a server {err}")
+    }
+
+    None
+}
+
 #[rrplug::concommand]
 fn test_connect() -> Option<()> {
     _ = PLUGIN
@@ -194,10 +703,11 @@ fn test_connect() -> Option<()> {
 }
 
 unsafe extern "C" fn disconnect_hook(ccommand: *const rrplug::bindings::cvar::command::CCommand) {
+    *crate::PLUGIN.wait().joined_server.lock() = None;
     _ = crate::PLUGIN
         .wait()
         .send_runframe
-        .send(LocalMessage::NewOrder(compartya_shared::Order::LeaveServer));
+        .send(LocalMessage::NewOrder(Order::LeaveServer));
 
     ORIGINAL_DISCONNECT.get().unwrap()(ccommand);
 }
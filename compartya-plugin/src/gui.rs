@@ -1,37 +1,127 @@
+use compartya_shared::{sanitize_name, LanAnnouncement, OrderStatus};
+use crossbeam_channel::Sender;
 use hudhook::{
     hooks::{dx11::ImguiDx11Hooks, ImguiRenderLoop},
     Hudhook,
 };
 use imgui::*;
 use rrplug::{high::UnsafeHandle, prelude::*};
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, mpsc::Receiver},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    lan::LAN_PARTY_STALE,
+    launch_args::{parse_host_addr, to_password},
+    link_stats::LinkStats,
+    local_addr::{self, Interface},
+    nat::NatType,
+    server_ranking::ServerCriteria,
+    LocalMessage, DEFAULT_PARTY_SIZE,
+};
 
-use crate::LocalMessage;
+const LINK_STATS_REFRESH: Duration = Duration::from_secs(1);
+const CHAT_SCROLLBACK: usize = 100;
 
 struct ComPartyaHud {
     should_render: bool,
+    /// set when the setting changes so the window follows it once
+    collapse: Option<bool>,
     sender: Sender<LocalMessage>,
     recv: UnsafeHandle<Receiver<LocalMessage>>, // nah it's safe should be single threaded, locking this is not required
     hosting_lobby: bool,
     lobby_uid: Option<String>,
-    party: Vec<String>,
+    party_link: Option<String>,
+    reconnecting: bool,
+    /// `None` while a check is running
+    nat: Option<NatType>,
+    party: Vec<(String, String)>,
+    link_stats: Vec<(String, LinkStats)>,
+    order_statuses: Vec<(String, OrderStatus)>,
+    mod_check: Option<(Vec<(String, Option<Vec<String>>)>, bool)>,
+    order_staged: bool,
+    last_link_stats_request: Instant,
+    chat: Vec<(String, String)>,
+    chat_input: String,
+    ready_prompt: Option<Instant>,
+    ready_tally: Option<(Vec<(String, Option<bool>)>, bool)>,
+    kick_reason: String,
+    max_party_size: i32,
+    search_playlist: String,
+    search_map: String,
+    search_region: String,
+    locked: bool,
+    notice: Option<String>,
+    nickname: String,
+    fallback_server: String,
+    mirror_chat: bool,
+    has_order: bool,
+    interfaces: Vec<Interface>,
+    lan_parties: Vec<(LanAnnouncement, SocketAddr, Instant)>,
+    /// 0 is automatic, the rest index into `interfaces` shifted by one
+    interface: usize,
+    /// what the networking thread actually listens on; `None` is automatic
+    bound_interface: Option<Ipv4Addr>,
     password: String,
     target_lobby_uid: String,
+    direct_addr: String,
 }
 
 impl ComPartyaHud {
     fn new(sender: Sender<LocalMessage>, recv: Receiver<LocalMessage>) -> Self {
         Self {
-            should_render: true,
+            should_render: crate::PLUGIN.wait().settings.lock().gui_collapsed,
+            collapse: None,
             sender,
             recv: unsafe { UnsafeHandle::new(recv) },
             hosting_lobby: false,
             party: Vec::new(),
+            link_stats: Vec::new(),
+            order_statuses: Vec::new(),
+            mod_check: None,
+            order_staged: false,
+            last_link_stats_request: Instant::now(),
+            chat: Vec::new(),
+            chat_input: String::new(),
+            ready_prompt: None,
+            ready_tally: None,
+            kick_reason: String::new(),
+            max_party_size: DEFAULT_PARTY_SIZE as i32,
+            search_playlist: String::new(),
+            search_map: String::new(),
+            search_region: String::new(),
+            locked: false,
+            notice: None,
+            nickname: String::new(),
+            fallback_server: crate::PLUGIN.wait().settings.lock().fallback_server.clone(),
+            mirror_chat: crate::PLUGIN.wait().settings.lock().mirror_chat,
+            has_order: false,
+            interfaces: local_addr::interfaces(),
+            lan_parties: Vec::new(),
+            interface: 0,
+            bound_interface: None,
             lobby_uid: None,
+            party_link: None,
+            reconnecting: false,
+            nat: None,
             password: String::new(),
             target_lobby_uid: String::new(),
+            direct_addr: String::new(),
         }
     }
+
+    /// the combo entry for an address, automatic if it's gone
+    fn interface_index(&self, ip: Option<Ipv4Addr>) -> usize {
+        ip.and_then(|ip| {
+            self.interfaces
+                .iter()
+                .position(|interface| interface.ip == ip)
+        })
+        .map(|index| index + 1)
+        .unwrap_or_default()
+    }
 }
 
 impl ImguiRenderLoop for ComPartyaHud {
@@ -40,17 +130,73 @@ impl ImguiRenderLoop for ComPartyaHud {
     }
 
     fn render(&mut self, ui: &mut Ui) {
-        if let Ok(recved) = self.recv.get_mut().try_recv() {
+        while let Ok(recved) = self.recv.get_mut().try_recv() {
             match recved {
                 LocalMessage::IsHost(hosting) => {
                     self.hosting_lobby = hosting;
 
                     self.party.clear();
+                    self.order_statuses.clear();
+                    self.mod_check = None;
+                    self.order_staged = false;
+                    self.ready_tally = None;
+                    self.max_party_size = DEFAULT_PARTY_SIZE as i32;
+                    self.locked = false;
+                }
+                LocalMessage::LobbyUid(uid) => {
+                    if uid.is_none() {
+                        self.party_link = None;
+                        self.ready_prompt = None;
+                        self.ready_tally = None;
+                        self.has_order = false;
+                    }
+
+                    self.lobby_uid = uid
+                }
+                LocalMessage::ReadyPrompt(deadline) => self.ready_prompt = deadline,
+                LocalMessage::HasOrder(has_order) => self.has_order = has_order,
+                LocalMessage::PartyLink(link) => self.party_link = Some(link),
+                LocalMessage::LanAnnounced(announcement, addr) => {
+                    let addr = SocketAddr::new(addr.ip(), announcement.port);
+
+                    self.lan_parties.retain(|(_, party, _)| *party != addr);
+                    self.lan_parties.push((announcement, addr, Instant::now()));
+                }
+                LocalMessage::Notice(notice) => self.notice = Some(notice),
+                LocalMessage::ReadyTally(answers, open) => self.ready_tally = Some((answers, open)),
+                LocalMessage::Reconnecting(reconnecting) => self.reconnecting = reconnecting,
+                LocalMessage::NatType(nat) => self.nat = Some(nat),
+                LocalMessage::SettingsChanged(settings) => {
+                    if settings.gui_collapsed != self.should_render {
+                        self.should_render = settings.gui_collapsed;
+                        self.collapse = Some(settings.gui_collapsed);
+                    }
+
+                    self.fallback_server = settings.fallback_server;
+                    self.mirror_chat = settings.mirror_chat;
+                }
+                LocalMessage::LinkStats(stats) => self.link_stats = stats,
+                LocalMessage::BindTo(ip) => {
+                    self.bound_interface = ip;
+                    self.interface = self.interface_index(ip);
+                }
+                LocalMessage::OrderStatuses(statuses) => self.order_statuses = statuses,
+                LocalMessage::ModCheckReport(report, open) => self.mod_check = Some((report, open)),
+                LocalMessage::OrderStaged(staged) => self.order_staged = staged,
+                LocalMessage::ChatMessage(sender, text) => {
+                    if self.chat.len() == CHAT_SCROLLBACK {
+                        self.chat.remove(0);
+                    }
+                    self.chat.push((sender, text));
+                }
+                LocalMessage::NewConnection(id, name) => {
+                    match self.party.iter_mut().find(|(cmp_id, _)| &id == cmp_id) {
+                        Some(member) => member.1 = name,
+                        None => self.party.push((id, name)),
+                    }
                 }
-                LocalMessage::LobbyUid(uid) => self.lobby_uid = uid,
-                LocalMessage::NewConnection(id) => self.party.push(id),
                 LocalMessage::DroppedConnection(id) => {
-                    if let Some(index) = self.party.iter().position(|cmp_id| &id == cmp_id) {
+                    if let Some(index) = self.party.iter().position(|(cmp_id, _)| &id == cmp_id) {
                         self.party.swap_remove(index);
                     }
                 }
@@ -58,15 +204,38 @@ impl ImguiRenderLoop for ComPartyaHud {
             }
         }
 
+        if self.lobby_uid.is_some() && self.last_link_stats_request.elapsed() > LINK_STATS_REFRESH {
+            self.last_link_stats_request = Instant::now();
+            _ = self.sender.send(LocalMessage::GetLinkStats);
+        }
+
+        let (collapsed, condition) = match self.collapse.take() {
+            Some(collapsed) => (collapsed, Condition::Always),
+            None => (self.should_render, Condition::FirstUseEver),
+        };
+
         ui.window("partya")
-            .collapsed(self.should_render, Condition::FirstUseEver) // change later to hook toggle console and a cmd maybe
-            .size([320., 320.], Condition::Always)
+            .collapsed(collapsed, condition)
+            .size([320., 480.], Condition::Always)
             .position([0., 0.], Condition::Always)
             .movable(false)
             .build(|| {
                 if let Some(uid) = self.lobby_uid.as_ref() {
                     if self.hosting_lobby {
                         ui.text(format!("Hosting Lobby: {}", uid));
+
+                        if let Some(link) = self.party_link.as_ref() {
+                            ui.same_line();
+                            if ui.small_button("copy party link") {
+                                ui.set_clipboard_text(link);
+                            }
+                            ui.same_line();
+                            if ui.small_button("revoke") {
+                                _ = self.sender.send(LocalMessage::RevokeInvite);
+                            }
+                        }
+                    } else if self.reconnecting {
+                        ui.text(format!("Reconnecting to Party: {}…", uid));
                     } else {
                         ui.text(format!("Connected to Party: {}", uid));
                     }
@@ -74,6 +243,31 @@ impl ImguiRenderLoop for ComPartyaHud {
                     ui.text("Hosting Lobby: Without STUN server connection");
                 } else {
                     ui.text("Not Connected To Any Party: ");
+
+                    if let Some(notice) = self.notice.as_ref() {
+                        ui.text_wrapped(notice);
+                    }
+                }
+
+                match self.nat {
+                    Some(nat) => ui.text(format!("NAT: {nat}")),
+                    None => ui.text("NAT: checking…"),
+                }
+
+                ui.same_line();
+                if ui.small_button("check nat") {
+                    self.nat = None;
+                    _ = self.sender.send(LocalMessage::CheckNat);
+                }
+
+                if self.hosting_lobby
+                    && self.nat.is_some_and(NatType::hole_punching_unlikely)
+                    && !crate::PLUGIN.wait().lan_mode.load(Ordering::Relaxed)
+                {
+                    ui.text_wrapped(format!(
+                        "members will likely fail to connect through this nat; forward udp port {} or have them connect directly",
+                        crate::PLUGIN.wait().local_port.load(Ordering::Relaxed)
+                    ));
                 }
 
                 if self.hosting_lobby {
@@ -82,11 +276,201 @@ impl ImguiRenderLoop for ComPartyaHud {
                             LocalMessage::ExecuteConCommand("p_order_to_this_server".to_string()),
                         )));
                     }
+
+                    if ui.button("ready check") {
+                        _ = self.sender.send(LocalMessage::StartReadyCheck);
+                    }
+
+                    if ui.collapsing_header("find a server", TreeNodeFlags::empty()) {
+                        ui.input_text("playlist", &mut self.search_playlist)
+                            .hint("any")
+                            .build();
+                        ui.input_text("map", &mut self.search_map)
+                            .hint("any")
+                            .build();
+                        ui.input_text("region", &mut self.search_region)
+                            .hint("any")
+                            .build();
+
+                        if ui.button("find a server for everyone") {
+                            let filter = |value: &String| {
+                                Some(value.trim().to_string()).filter(|value| !value.is_empty())
+                            };
+
+                            _ = self.sender.send(LocalMessage::FindServer(ServerCriteria {
+                                playlist: filter(&self.search_playlist),
+                                map: filter(&self.search_map),
+                                region: filter(&self.search_region),
+                                ..Default::default()
+                            }));
+                        }
+                    }
+
+                    if ui.button("check mods") {
+                        _ = self.sender.send(LocalMessage::CheckMods);
+                    }
+
+                    if let Some((report, open)) = self.mod_check.as_ref() {
+                        let mut everyone_can_join = true;
+
+                        for (name, problems) in report.iter() {
+                            match problems {
+                                None if *open => {
+                                    everyone_can_join = false;
+                                    ui.text(format!("  {name}: checking…"));
+                                }
+                                None => {
+                                    everyone_can_join = false;
+                                    ui.text(format!("  {name} didn't answer"));
+                                }
+                                Some(problems) if !problems.is_empty() => {
+                                    everyone_can_join = false;
+                                    ui.text_wrapped(format!(
+                                        "  {name} can't join: {}",
+                                        problems.join(", ")
+                                    ));
+                                }
+                                Some(_) => {}
+                            }
+                        }
+
+                        if everyone_can_join {
+                            ui.text("everyone has the mods for this server");
+                        }
+                    }
+
+                    if self.order_staged {
+                        ui.text("the party follows once everyone has the mods");
+
+                        if ui.button("send the order anyway") {
+                            _ = self.sender.send(LocalMessage::ConfirmOrder);
+                        }
+                    }
+
+                    if ui.checkbox("locked", &mut self.locked) {
+                        _ = self.sender.send(LocalMessage::LockLobby(self.locked));
+                    }
+
+                    if ui
+                        .input_int("max party size", &mut self.max_party_size)
+                        .build()
+                    {
+                        self.max_party_size = self.max_party_size.clamp(2, u8::MAX as i32);
+                        _ = self
+                            .sender
+                            .send(LocalMessage::SetPartySize(self.max_party_size as u8));
+                    }
+
+                    if let Some((answers, open)) = self.ready_tally.as_ref() {
+                        let ready = answers.iter().filter(|(_, answer)| *answer == Some(true));
+
+                        ui.text(format!(
+                            "Ready: {}/{}{}",
+                            ready.count(),
+                            answers.len(),
+                            if *open { "" } else { " (done)" }
+                        ));
+
+                        for (name, answer) in answers.iter() {
+                            ui.text(format!(
+                                "  {name}: {}",
+                                match answer {
+                                    Some(true) => "ready",
+                                    Some(false) => "not ready",
+                                    None if *open => "waiting…",
+                                    None => "no answer",
+                                }
+                            ));
+                        }
+                    }
                 } else if self.lobby_uid.is_some() {
-                    if ui.button("Repeat Order From Host") {
-                        _ = self.sender.send(LocalMessage::GetCachedOrder);
+                    if self.has_order {
+                        if ui.button("Repeat Order From Host") {
+                            _ = self.sender.send(LocalMessage::GetCachedOrder);
+                        }
+                    } else if !self.fallback_server.is_empty() {
+                        ui.text("no order from the host yet");
+
+                        if ui.button("Go To Fallback Server") {
+                            _ = self.sender.send(LocalMessage::GetCachedOrder);
+                        }
+                    } else {
+                        ui.text("no order from the host yet");
+                    }
+
+                    if let Some(deadline) = self.ready_prompt {
+                        let left = deadline.saturating_duration_since(Instant::now());
+
+                        if left.is_zero() {
+                            self.ready_prompt = None;
+                        } else {
+                            ui.text(format!("Host asks if you're ready ({}s)", left.as_secs()));
+
+                            if ui.button("ready") {
+                                _ = self.sender.send(LocalMessage::AnswerReady(true));
+                                self.ready_prompt = None;
+                            }
+
+                            ui.same_line();
+
+                            if ui.button("not ready") {
+                                _ = self.sender.send(LocalMessage::AnswerReady(false));
+                                self.ready_prompt = None;
+                            }
+                        }
                     }
                 } else if self.lobby_uid.is_none() {
+                    if ui
+                        .input_text("nickname", &mut self.nickname)
+                        .hint("optional")
+                        .build()
+                    {
+                        crate::PLUGIN.wait().profile.lock().nickname =
+                            sanitize_name(&self.nickname);
+                    }
+
+                    let labels = std::iter::once("automatic".to_string())
+                        .chain(
+                            self.interfaces
+                                .iter()
+                                .map(|interface| format!("{} ({})", interface.name, interface.ip)),
+                        )
+                        .collect::<Vec<String>>();
+
+                    if ui.combo_simple_string("network interface", &mut self.interface, &labels) {
+                        _ = self.sender.send(LocalMessage::BindTo(
+                            self.interface
+                                .checked_sub(1)
+                                .and_then(|index| self.interfaces.get(index))
+                                .map(|interface| interface.ip),
+                        ));
+                    }
+
+                    ui.same_line();
+                    if ui.small_button("refresh") {
+                        self.interfaces = local_addr::interfaces();
+                        self.interface = self.interface_index(self.bound_interface);
+                    }
+
+                    if ui
+                        .input_text("fallback server", &mut self.fallback_server)
+                        .hint("server id, optional")
+                        .chars_noblank(true)
+                        .enter_returns_true(true)
+                        .build()
+                    {
+                        // goes through the convar so it's saved with the other settings
+                        let id = self
+                            .fallback_server
+                            .chars()
+                            .filter(|c| !matches!(c, '"' | ';') && !c.is_control())
+                            .collect::<String>();
+
+                        _ = self.sender.send(LocalMessage::ForwardToEngine(Box::new(
+                            LocalMessage::ExecuteConCommand(format!("p_fallback_server \"{id}\"")),
+                        )));
+                    }
+
                     ui.input_text("lobby password", &mut self.password)
                         .chars_noblank(true)
                         .build();
@@ -99,34 +483,98 @@ impl ImguiRenderLoop for ComPartyaHud {
                         ui.text("uid is too short");
                     } else if self.target_lobby_uid.len() > 8 {
                         ui.text("uid is too long!");
+                    } else if to_password(&self.password).is_none() {
+                        ui.text("password is too long!");
                     } else if ui.button("connect to lobby") {
-                        let mut password = self.password.chars().collect::<Vec<char>>();
-                        password.resize(8, ' ');
-
                         if let Some((uid, password)) = self
                             .target_lobby_uid
                             .chars()
                             .collect::<Vec<char>>()
                             .try_into()
                             .ok()
-                            .and_then(|uid| Some((uid, password.try_into().ok()?)))
+                            .and_then(|uid| Some((uid, to_password(&self.password)?)))
                         {
                             _ = self
                                 .sender
-                                .send(LocalMessage::ConnectToLobby(uid, password));
+                                .send(LocalMessage::ConnectToLobby(uid, password, None));
 
+                            self.notice = None;
                             self.lobby_uid = Some(self.target_lobby_uid.clone());
                             // just to not send mutitple connect to lobby requests
                         }
                     }
 
-                    if self.password.len() > 8 {
-                        ui.text("password is to long!");
-                    } else if ui.button("start lobby") {
-                        let mut password = self.password.chars().collect::<Vec<char>>();
-                        password.resize(8, ' ');
+                    ui.input_text("host address", &mut self.direct_addr)
+                        .hint("ip:port, optional")
+                        .chars_noblank(true)
+                        .build();
+
+                    if !self.direct_addr.is_empty() {
+                        match parse_host_addr(&self.direct_addr) {
+                            None => ui.text("not an address"),
+                            Some(_) if to_password(&self.password).is_none() => {
+                                ui.text("password is too long!")
+                            }
+                            Some(addr) => {
+                                if ui.button("connect directly") {
+                                    if let Some(password) = to_password(&self.password) {
+                                        _ = self.sender.send(LocalMessage::ConnectDirect(
+                                            addr, None, password,
+                                        ));
+
+                                        self.notice = None;
+                                        self.lobby_uid = Some(addr.to_string());
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    self.lan_parties
+                        .retain(|(_, _, seen)| seen.elapsed() < LAN_PARTY_STALE);
 
-                        if let Ok(password) = password.try_into() {
+                    if !self.lan_parties.is_empty() {
+                        ui.text("LAN parties:");
+
+                        if to_password(&self.password).is_none() {
+                            ui.text("password is too long!");
+                        }
+                    }
+
+                    for (party, addr, _) in self.lan_parties.iter() {
+                        ui.text(format!(
+                            "  {} ({}/{}){}",
+                            party.host,
+                            party.members,
+                            party.max_size,
+                            if party.locked { " locked" } else { "" }
+                        ));
+
+                        ui.same_line();
+                        if ui.small_button(format!("join##{addr}")) {
+                            if let Some(password) = to_password(&self.password) {
+                                _ = self.sender.send(LocalMessage::ConnectDirect(
+                                    *addr,
+                                    Some(party.lobby_id),
+                                    password,
+                                ));
+
+                                self.notice = None;
+                                self.lobby_uid = Some(party.lobby_id.iter().collect());
+                            }
+                        }
+                    }
+
+                    let lan_mode = &crate::PLUGIN.wait().lan_mode;
+                    let mut lan = lan_mode.load(Ordering::Relaxed);
+                    if ui.checkbox("LAN party (no matchmaking server)", &mut lan) {
+                        lan_mode.store(lan, Ordering::Relaxed);
+                    }
+
+                    if to_password(&self.password).is_none() {
+                        ui.text("password is too long!");
+                    } else if ui.button("start lobby") {
+                        if let Some(password) = to_password(&self.password) {
                             _ = self.sender.send(LocalMessage::BecomeHost(password));
 
                             self.hosting_lobby = true; // just to not send too many become host messages
@@ -144,15 +592,137 @@ impl ImguiRenderLoop for ComPartyaHud {
 
                 ui.separator();
 
+                for (peer, stats) in self
+                    .link_stats
+                    .iter()
+                    .filter(|(peer, _)| !self.party.iter().any(|(id, _)| id == peer))
+                {
+                    ui.text(format!("{peer} {}", format_link_stats(stats)));
+                }
+
                 ui.text("Party Members");
 
-                for member in self.party.iter() {
-                    ui.text(member);
+                if self.hosting_lobby {
+                    ui.text(format!(
+                        "{} (host)",
+                        crate::PLUGIN.wait().profile.lock().display_name()
+                    ));
+                }
+
+                if self.hosting_lobby && !self.party.is_empty() {
+                    ui.input_text("kick reason", &mut self.kick_reason)
+                        .hint("optional")
+                        .build();
+                }
+
+                let mut kick = None;
+                for (id, name) in self.party.iter() {
+                    match self.link_stats.iter().find(|(peer, _)| peer == id) {
+                        Some((_, stats)) => ui.text(format!("{name} {}", format_link_stats(stats))),
+                        None => ui.text(name),
+                    }
+
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text(id);
+                    }
+
+                    if let Some((_, status)) =
+                        self.order_statuses.iter().find(|(member, _)| member == id)
+                    {
+                        ui.same_line();
+                        match status {
+                            OrderStatus::Connected => ui.text_colored([0.3, 0.9, 0.3, 1.], "●"),
+                            status if status.is_final() => {
+                                ui.text_colored([0.9, 0.3, 0.3, 1.], "●")
+                            }
+                            _ => ui.text_colored([0.9, 0.8, 0.3, 1.], "●"),
+                        }
+
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text(status.to_string());
+                        }
+                    }
+
+                    if self.hosting_lobby {
+                        ui.same_line();
+                        if ui.small_button(format!("first##{id}")) {
+                            _ = self
+                                .sender
+                                .send(LocalMessage::PrioritizeJoin(vec![id.clone()]));
+                        }
+
+                        ui.same_line();
+                        if ui.small_button(format!("kick##{id}")) {
+                            kick = Some((id.clone(), false));
+                        }
+
+                        ui.same_line();
+                        if ui.small_button(format!("ban##{id}")) {
+                            kick = Some((id.clone(), true));
+                        }
+                    }
+                }
+
+                if let Some((id, ban)) = kick {
+                    _ = self.sender.send(LocalMessage::Kick(
+                        id,
+                        std::mem::take(&mut self.kick_reason),
+                        ban,
+                    ));
+                }
+
+                ui.separator();
+
+                ui.text("Party Chat");
+
+                ui.child_window("chat scrollback")
+                    .size([0., 120.])
+                    .border(true)
+                    .build(|| {
+                        for (sender, text) in self.chat.iter() {
+                            ui.text_wrapped(format!("{sender}: {text}"));
+                        }
+
+                        if ui.scroll_y() >= ui.scroll_max_y() {
+                            ui.set_scroll_here_y_with_ratio(1.);
+                        }
+                    });
+
+                if ui
+                    .input_text("##chat input", &mut self.chat_input)
+                    .enter_returns_true(true)
+                    .build()
+                    && !self.chat_input.trim().is_empty()
+                {
+                    _ = self
+                        .sender
+                        .send(LocalMessage::SendChat(std::mem::take(&mut self.chat_input)));
+                }
+
+                if ui.checkbox("show in game chat", &mut self.mirror_chat) {
+                    _ = self.sender.send(LocalMessage::ForwardToEngine(Box::new(
+                        LocalMessage::ExecuteConCommand(format!(
+                            "p_mirror_chat {}",
+                            self.mirror_chat as u8
+                        )),
+                    )));
                 }
             });
     }
 }
 
+fn format_link_stats(stats: &LinkStats) -> String {
+    match stats.rtt {
+        Some(rtt) => format!(
+            "{}ms ±{}ms {:.0}% loss",
+            rtt.as_millis(),
+            stats.jitter.as_millis(),
+            stats.loss * 100.
+        ),
+        None => "measuring…".to_string(),
+    }
+}
+
 pub fn init_gui(sender: Sender<LocalMessage>, recv: Receiver<LocalMessage>) {
     static mut INIT: bool = false;
 
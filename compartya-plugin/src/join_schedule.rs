@@ -0,0 +1,138 @@
+use compartya_shared::PlayerUid;
+use std::time::Duration;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct JoinSchedule {
+    /// who gets the order and how long after the first one
+    pub joins: Vec<(PlayerUid, Duration)>,
+    /// didn't fit; they get a slot when someone ahead of them fails to join
+    pub waiting: Vec<PlayerUid>,
+}
+
+/// spaces out the joins so members don't race each other for the last slots
+///
+/// members in `priority` go first in that order, everyone else keeps their order in `members`;
+/// `None` slots means we don't know so everyone gets a turn
+pub fn schedule_joins(
+    members: &[PlayerUid],
+    priority: &[PlayerUid],
+    free_slots: Option<u32>,
+    spacing: Duration,
+) -> JoinSchedule {
+    let mut ordered = Vec::with_capacity(members.len());
+    for uid in priority.iter().chain(members) {
+        if members.contains(uid) && !ordered.contains(uid) {
+            ordered.push(*uid);
+        }
+    }
+
+    let fits = free_slots
+        .map(|slots| (slots as usize).min(ordered.len()))
+        .unwrap_or(ordered.len());
+    let waiting = ordered.split_off(fits);
+
+    JoinSchedule {
+        joins: ordered
+            .into_iter()
+            .zip(0u32..)
+            .map(|(uid, turn)| (uid, spacing * turn))
+            .collect(),
+        waiting,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: PlayerUid = ['a'; 5];
+    const B: PlayerUid = ['b'; 5];
+    const C: PlayerUid = ['c'; 5];
+    const D: PlayerUid = ['d'; 5];
+    const SPACING: Duration = Duration::from_secs(2);
+
+    fn order(schedule: &JoinSchedule) -> Vec<PlayerUid> {
+        schedule.joins.iter().map(|(uid, _)| *uid).collect()
+    }
+
+    #[test]
+    fn priority_goes_first_in_order() {
+        let schedule = schedule_joins(&[A, B, C, D], &[D, B], None, SPACING);
+
+        assert_eq!(order(&schedule), [D, B, A, C]);
+        assert!(schedule.waiting.is_empty());
+    }
+
+    #[test]
+    fn priority_ignores_duplicates_and_strangers() {
+        let stranger = ['z'; 5];
+        let schedule = schedule_joins(&[A, B, C], &[C, stranger, C, A, stranger], None, SPACING);
+
+        assert_eq!(order(&schedule), [C, A, B]);
+    }
+
+    #[test]
+    fn unknown_slots_fit_everyone() {
+        let schedule = schedule_joins(&[A, B, C], &[], None, SPACING);
+
+        assert_eq!(order(&schedule), [A, B, C]);
+        assert!(schedule.waiting.is_empty());
+    }
+
+    #[test]
+    fn no_slots_leaves_everyone_waiting() {
+        let schedule = schedule_joins(&[A, B, C], &[B], Some(0), SPACING);
+
+        assert!(schedule.joins.is_empty());
+        assert_eq!(schedule.waiting, [B, A, C]);
+    }
+
+    #[test]
+    fn fewer_slots_than_members() {
+        let schedule = schedule_joins(&[A, B, C, D], &[C], Some(2), SPACING);
+
+        assert_eq!(order(&schedule), [C, A]);
+        assert_eq!(schedule.waiting, [B, D]);
+    }
+
+    #[test]
+    fn more_slots_than_members() {
+        let schedule = schedule_joins(&[A, B], &[], Some(10), SPACING);
+
+        assert_eq!(order(&schedule), [A, B]);
+        assert!(schedule.waiting.is_empty());
+    }
+
+    #[test]
+    fn exactly_enough_slots() {
+        let schedule = schedule_joins(&[A, B, C], &[], Some(3), SPACING);
+
+        assert_eq!(order(&schedule), [A, B, C]);
+        assert!(schedule.waiting.is_empty());
+    }
+
+    #[test]
+    fn joins_are_spaced_out() {
+        let schedule = schedule_joins(&[A, B, C], &[], None, SPACING);
+
+        assert_eq!(
+            schedule.joins,
+            [(A, Duration::ZERO), (B, SPACING), (C, SPACING * 2)]
+        );
+    }
+
+    #[test]
+    fn zero_spacing_sends_at_once() {
+        let schedule = schedule_joins(&[A, B, C], &[], None, Duration::ZERO);
+
+        assert!(schedule.joins.iter().all(|(_, delay)| delay.is_zero()));
+    }
+
+    #[test]
+    fn empty_party() {
+        assert_eq!(
+            schedule_joins(&[], &[A], Some(4), SPACING),
+            JoinSchedule::default()
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use compartya_shared::LanAnnouncement;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+pub const LAN_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 67, 80);
+pub const LAN_PORT: u16 = 12353;
+pub const LAN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// parties that stopped announcing for this long are gone
+pub const LAN_PARTY_STALE: Duration = Duration::from_secs(6);
+
+/// every instance shares the port so several games on one machine all hear the announcements
+pub fn listen() -> io::Result<crossbeam_channel::Receiver<(LanAnnouncement, SocketAddr)>> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LAN_PORT)).into())?;
+    socket.join_multicast_v4(&LAN_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+
+    let socket = UdpSocket::from(socket);
+    let (send, recv) = crossbeam_channel::unbounded();
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 512];
+
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                // windows reports icmp errors from earlier sends here
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    log::warn!("stopped listening for LAN parties: {err}");
+                    return;
+                }
+            };
+
+            let Some(announcement) = LanAnnouncement::decode(&buf[..len]) else {
+                continue;
+            };
+
+            if send.send((announcement, addr)).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(recv)
+}
+
+pub fn announce(announcement: &LanAnnouncement) -> io::Result<()> {
+    let Some(packet) = announcement.encode() else {
+        return Ok(());
+    };
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.send_to(&packet, (LAN_GROUP, LAN_PORT))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement() -> LanAnnouncement {
+        LanAnnouncement {
+            lobby_id: ['l', 'a', 'n', 't', 'e', 's', 't', 's'],
+            host: "host".to_string(),
+            members: 2,
+            max_size: 16,
+            locked: true,
+            port: 12352,
+        }
+    }
+
+    #[test]
+    fn announcements_round_trip() {
+        let announcement = announcement();
+        let packet = announcement.encode().unwrap();
+
+        assert_eq!(LanAnnouncement::decode(&packet), Some(announcement));
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut packet = announcement().encode().unwrap();
+        packet[0] = b'X';
+
+        assert_eq!(LanAnnouncement::decode(&packet), None);
+        assert_eq!(LanAnnouncement::decode(&packet[4..]), None);
+        assert_eq!(LanAnnouncement::decode(b"CPL"), None);
+    }
+
+    #[test]
+    fn host_names_are_sanitized() {
+        let announcement = LanAnnouncement {
+            host: "ho\nst ".to_string(),
+            ..announcement()
+        };
+        let packet = announcement.encode().unwrap();
+
+        assert_eq!(LanAnnouncement::decode(&packet).unwrap().host, "host");
+
+        let announcement = LanAnnouncement {
+            host: "\n".to_string(),
+            ..announcement
+        };
+        let packet = announcement.encode().unwrap();
+
+        assert_eq!(LanAnnouncement::decode(&packet), None);
+    }
+
+    // only linux reliably loops multicast back to every socket sharing the port
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn every_listener_hears_announcements() {
+        let first = listen().unwrap();
+        let second = listen().unwrap();
+        let announcement = announcement();
+
+        announce(&announcement).unwrap();
+
+        for listener in [first, second] {
+            let heard = std::iter::from_fn(|| listener.recv_timeout(Duration::from_secs(2)).ok())
+                .find(|(heard, _)| heard.lobby_id == announcement.lobby_id)
+                .map(|(heard, _)| heard);
+
+            assert_eq!(heard, Some(announcement.clone()));
+        }
+    }
+}
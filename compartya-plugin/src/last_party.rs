@@ -0,0 +1,272 @@
+use compartya_shared::{crypto::password_verifier, LobbyUid, Password, PlayerUid, ResumeToken};
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const FILE_NAME: &str = "compartya_last_party.toml";
+const DEFAULT_PROFILE: &str = "R2Northstar";
+/// the host forgets suspended members way sooner; this only keeps us from retrying dead parties
+pub const LAST_PARTY_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// what a member needs to resume their place in a party after a restart
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct LastParty {
+    pub lobby_id: String,
+    pub password_verifier: String,
+    pub uid: String,
+    pub resume_token: String,
+    /// unix seconds
+    pub saved_at: u64,
+    /// the matchmaking server the lobby is registered with
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+impl LastParty {
+    pub fn new(
+        lobby_id: LobbyUid,
+        password: &Password,
+        uid: PlayerUid,
+        resume_token: ResumeToken,
+        server: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            lobby_id: lobby_id.iter().collect(),
+            password_verifier: password_verifier(&lobby_id, password),
+            uid: uid.iter().collect(),
+            resume_token: resume_token.iter().collect(),
+            saved_at: unix_secs(SystemTime::now()),
+            server: server.map(|server| server.to_string()),
+        }
+    }
+
+    pub fn lobby_id(&self) -> Option<LobbyUid> {
+        to_chars(&self.lobby_id)
+    }
+
+    pub fn uid(&self) -> Option<PlayerUid> {
+        to_chars(&self.uid)
+    }
+
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        to_chars(&self.resume_token)
+    }
+
+    pub fn server(&self) -> Option<SocketAddr> {
+        self.server.as_ref()?.parse().ok()
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        unix_secs(now).saturating_sub(self.saved_at) > LAST_PARTY_EXPIRY.as_secs()
+    }
+
+    pub fn matches(&self, lobby_id: LobbyUid, password: &Password) -> bool {
+        self.lobby_id == lobby_id.iter().collect::<String>()
+            && self.password_verifier == password_verifier(&lobby_id, password)
+    }
+}
+
+/// northstar passes the profile as `-profile=<dir>`
+pub fn profile_dir() -> PathBuf {
+    env::args()
+        .find_map(|arg| arg.strip_prefix("-profile=").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_PROFILE))
+}
+
+/// the last party if there is one that isn't stale
+pub fn load() -> Option<LastParty> {
+    load_from(&profile_dir().join(FILE_NAME))
+}
+
+pub fn save(party: &LastParty) {
+    save_to(&profile_dir().join(FILE_NAME), party)
+}
+
+/// keeps the party from expiring while we are still in it; only writes once a minute
+pub fn touch(party: &mut LastParty) {
+    touch_in(&profile_dir().join(FILE_NAME), party, SystemTime::now())
+}
+
+pub fn forget() {
+    _ = fs::remove_file(profile_dir().join(FILE_NAME));
+}
+
+fn load_from(path: &Path) -> Option<LastParty> {
+    let party = toml::from_str::<LastParty>(&fs::read_to_string(path).ok()?)
+        .map_err(|err| log::warn!("couldn't read {}: {err}", path.display()))
+        .ok()?;
+
+    if party.is_expired(SystemTime::now()) {
+        log::info!("the last party is too old to rejoin");
+        _ = fs::remove_file(path);
+        return None;
+    }
+
+    Some(party)
+}
+
+fn save_to(path: &Path, party: &LastParty) {
+    match toml::to_string(party) {
+        Ok(contents) => {
+            if let Err(err) = fs::write(path, contents) {
+                log::warn!("couldn't save the party to {}: {err}", path.display());
+            }
+        }
+        Err(err) => log::warn!("couldn't serialize the party: {err}"),
+    }
+}
+
+fn touch_in(path: &Path, party: &mut LastParty, now: SystemTime) {
+    let now = unix_secs(now);
+
+    if now.saturating_sub(party.saved_at) >= 60 {
+        party.saved_at = now;
+        save_to(path, party);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+fn to_chars<const N: usize>(value: &str) -> Option<[char; N]> {
+    value.chars().collect::<Vec<char>>().try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn party() -> LastParty {
+        LastParty::new(
+            ['a'; 8],
+            &['b'; 8],
+            ['c'; 5],
+            ['d'; 16],
+            Some("10.0.0.1:2000".parse().unwrap()),
+        )
+    }
+
+    /// a file of its own per test since they run in parallel
+    fn test_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "compartya_last_party_{}_{name}.toml",
+            std::process::id()
+        ));
+        _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn expires_after_half_an_hour() {
+        let party = party();
+        let saved_at = UNIX_EPOCH + Duration::from_secs(party.saved_at);
+
+        assert!(!party.is_expired(saved_at));
+        assert!(!party.is_expired(saved_at + LAST_PARTY_EXPIRY));
+        assert!(party.is_expired(saved_at + LAST_PARTY_EXPIRY + Duration::from_secs(1)));
+        // a clock that went backwards isn't a reason to forget the party
+        assert!(!party.is_expired(saved_at - Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn matches_the_lobby_and_password() {
+        let party = party();
+
+        assert!(party.matches(['a'; 8], &['b'; 8]));
+        assert!(!party.matches(['a'; 8], &['x'; 8]));
+        assert!(!party.matches(['x'; 8], &['b'; 8]));
+        assert_ne!(party.password_verifier, "bbbbbbbb");
+    }
+
+    #[test]
+    fn ids_come_back_out() {
+        let party = party();
+
+        assert_eq!(party.lobby_id(), Some(['a'; 8]));
+        assert_eq!(party.uid(), Some(['c'; 5]));
+        assert_eq!(party.resume_token(), Some(['d'; 16]));
+        assert_eq!(party.server(), Some("10.0.0.1:2000".parse().unwrap()));
+
+        let broken = LastParty {
+            uid: "too long".to_string(),
+            server: None,
+            ..party
+        };
+        assert_eq!(broken.uid(), None);
+        assert_eq!(broken.server(), None);
+    }
+
+    #[test]
+    fn touch_only_writes_once_a_minute() {
+        let path = test_file("touch");
+        let mut party = party();
+        let saved_at = party.saved_at;
+        let then = UNIX_EPOCH + Duration::from_secs(saved_at);
+
+        touch_in(&path, &mut party, then + Duration::from_secs(59));
+        assert_eq!(party.saved_at, saved_at);
+        assert!(!path.exists());
+
+        touch_in(&path, &mut party, then + Duration::from_secs(60));
+        assert_eq!(party.saved_at, saved_at + 60);
+        assert_eq!(load_from(&path), Some(party));
+
+        _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = test_file("round_trip");
+        let party = party();
+
+        save_to(&path, &party);
+        assert_eq!(load_from(&path), Some(party));
+
+        _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn stale_parties_are_forgotten() {
+        let path = test_file("stale");
+        let party = LastParty {
+            saved_at: party().saved_at - LAST_PARTY_EXPIRY.as_secs() - 1,
+            ..party()
+        };
+
+        save_to(&path, &party);
+        assert_eq!(load_from(&path), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn missing_or_corrupt_files_load_nothing() {
+        let path = test_file("corrupt");
+        assert_eq!(load_from(&path), None);
+
+        fs::write(&path, "lobby_id = [").unwrap();
+        assert_eq!(load_from(&path), None);
+
+        _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn old_files_without_a_server_still_load() {
+        let path = test_file("no_server");
+        let party = LastParty {
+            server: None,
+            ..party()
+        };
+
+        save_to(&path, &party);
+        assert!(!fs::read_to_string(&path).unwrap().contains("server"));
+        assert_eq!(load_from(&path), Some(party));
+
+        _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,495 @@
+use compartya_shared::{LobbyUid, Password};
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+
+pub const IP_ARG: &str = "compartya_ip";
+pub const PORT_ARG: &str = "compartya_port";
+pub const SERVERS_ARG: &str = "compartya_servers";
+pub const DEFAULT_PORT: u16 = 12352;
+
+const SCHEME: &str = "compartya:";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LaunchArgs {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// matchmaking servers to try before the configured ones
+    pub servers: Vec<String>,
+    pub uri: Option<CompartyaUri>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompartyaUri {
+    /// `compartya://open:<server id or name>`
+    OpenServer(String),
+
+    /// `compartya://lobby/<lobby id>?invite=<lobby password>&server=<matchmaking server>`
+    JoinLobby(LobbyUid, Password, Option<SocketAddr>),
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ArgError {
+    #[error("{0} needs a value")]
+    MissingValue(&'static str),
+
+    #[error("{0:?} isn't an ip address")]
+    InvalidIp(String),
+
+    #[error("{0:?} isn't a port")]
+    InvalidPort(String),
+
+    #[error("{0:?} isn't a compartya uri")]
+    NotCompartya(String),
+
+    #[error("unknown compartya action {0:?}")]
+    UnknownAction(String),
+
+    #[error("the uri doesn't say which server to open")]
+    MissingServer,
+
+    #[error("{0:?} isn't a lobby id")]
+    InvalidLobby(String),
+
+    #[error("the invite is longer than a lobby password")]
+    InvalidInvite,
+
+    #[error("{0:?} isn't a matchmaking server address")]
+    InvalidServer(String),
+
+    #[error("broken percent encoding in {0:?}")]
+    BadEncoding(String),
+}
+
+/// picks our options out of the game's command line and ignores everything else
+pub fn parse_launch_args(args: impl IntoIterator<Item = String>) -> Result<LaunchArgs, ArgError> {
+    let mut launch = LaunchArgs::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            IP_ARG => {
+                let value = args.next().ok_or(ArgError::MissingValue(IP_ARG))?;
+                launch.ip = Some(value.parse().map_err(|_| ArgError::InvalidIp(value))?);
+            }
+            PORT_ARG => {
+                let value = args.next().ok_or(ArgError::MissingValue(PORT_ARG))?;
+                launch.port = Some(value.parse().map_err(|_| ArgError::InvalidPort(value))?);
+            }
+            SERVERS_ARG => {
+                let value = args.next().ok_or(ArgError::MissingValue(SERVERS_ARG))?;
+                launch.servers = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|server| !server.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            _ if is_compartya_uri(&arg) => launch.uri = Some(parse_uri(&arg)?),
+            _ => {}
+        }
+    }
+
+    Ok(launch)
+}
+
+pub fn is_compartya_uri(arg: &str) -> bool {
+    arg.get(..SCHEME.len())
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+}
+
+/// windows hands us the uri as typed, browsers percent encode it and usually add a trailing `/`
+///
+/// `compartya://open:<id>`, `compartya:\open:<id>` and `compartya::%5Copen:<id>` are all the same
+///
+/// the same goes for `compartya://lobby/<id>` and `compartya://lobby:<id>`
+pub fn parse_uri(uri: &str) -> Result<CompartyaUri, ArgError> {
+    if !is_compartya_uri(uri) {
+        return Err(ArgError::NotCompartya(uri.to_string()));
+    }
+
+    // the query is decoded per value so an encoded `&` can't split it
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let decoded = percent_decode(path)?;
+    let rest = decoded[SCHEME.len()..].trim_start_matches(['/', '\\', ':']);
+
+    let (action, target) = rest
+        .split_once([':', '/'])
+        .ok_or_else(|| ArgError::UnknownAction(rest.to_string()))?;
+
+    match action.to_ascii_lowercase().as_str() {
+        "open" => {
+            let server = target.trim_end_matches('/').trim();
+
+            if server.is_empty() {
+                return Err(ArgError::MissingServer);
+            }
+
+            Ok(CompartyaUri::OpenServer(server.to_string()))
+        }
+        "lobby" => {
+            let lobby_id = target.trim_end_matches('/');
+
+            let param = |name: &str| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                    .map(|value| percent_decode(value.trim_end_matches('/')))
+                    .transpose()
+            };
+
+            let invite = param("invite")?.unwrap_or_default();
+            let server = param("server")?
+                .map(|server| server.parse().map_err(|_| ArgError::InvalidServer(server)))
+                .transpose()?;
+
+            Ok(CompartyaUri::JoinLobby(
+                lobby_id
+                    .chars()
+                    .collect::<Vec<char>>()
+                    .try_into()
+                    .map_err(|_| ArgError::InvalidLobby(lobby_id.to_string()))?,
+                to_password(&invite).ok_or(ArgError::InvalidInvite)?,
+                server,
+            ))
+        }
+        _ => Err(ArgError::UnknownAction(action.to_string())),
+    }
+}
+
+/// `ip:port` or just the ip for hosts on the default port
+pub fn parse_host_addr(addr: &str) -> Option<SocketAddr> {
+    let addr = addr.trim();
+
+    addr.parse::<SocketAddr>().ok().or_else(|| {
+        addr.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+    })
+}
+
+/// a link that launches the game straight into the party
+pub fn lobby_link(lobby_id: &str, password: &str, server: Option<SocketAddr>) -> String {
+    let query = [
+        Some(password.trim())
+            .filter(|password| !password.is_empty())
+            .map(|password| format!("invite={}", percent_encode(password))),
+        server.map(|server| format!("server={}", percent_encode(&server.to_string()))),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<String>>();
+
+    if query.is_empty() {
+        format!("compartya://lobby/{lobby_id}")
+    } else {
+        format!("compartya://lobby/{lobby_id}?{}", query.join("&"))
+    }
+}
+
+/// lobby passwords are padded with spaces like the gui does
+pub fn to_password(password: &str) -> Option<Password> {
+    let mut password = password.chars().collect::<Vec<char>>();
+
+    if password.len() > 8 {
+        return None;
+    }
+
+    password.resize(8, ' ');
+    password.try_into().ok()
+}
+
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+pub fn percent_decode(value: &str) -> Result<String, ArgError> {
+    let bad_encoding = || ArgError::BadEncoding(value.to_string());
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+
+        let high = iter.next().and_then(hex_value).ok_or_else(bad_encoding)?;
+        let low = iter.next().and_then(hex_value).ok_or_else(bad_encoding)?;
+        bytes.push(high << 4 | low);
+    }
+
+    String::from_utf8(bytes).map_err(|_| bad_encoding())
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOBBY: LobbyUid = ['a', 'b', 'c', 'd', 'e', 'f', 'g', 'h'];
+
+    fn args(args: &[&str]) -> Result<LaunchArgs, ArgError> {
+        parse_launch_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn open(server: &str) -> Result<CompartyaUri, ArgError> {
+        Ok(CompartyaUri::OpenServer(server.to_string()))
+    }
+
+    fn lobby(password: &str, server: Option<&str>) -> Result<CompartyaUri, ArgError> {
+        Ok(CompartyaUri::JoinLobby(
+            LOBBY,
+            to_password(password).unwrap(),
+            server.map(|server| server.parse().unwrap()),
+        ))
+    }
+
+    #[test]
+    fn open_forms() {
+        for uri in [
+            "compartya://open:abc123",
+            "compartya://open:abc123/",
+            "compartya:\\open:abc123",
+            "compartya:\\open:abc123/",
+            "compartya::%5Copen:abc123",
+            "compartya::%5Copen:abc123/",
+            "COMPARTYA://OPEN:abc123",
+        ] {
+            assert_eq!(parse_uri(uri), open("abc123"), "{uri}");
+        }
+    }
+
+    #[test]
+    fn open_decodes_names() {
+        assert_eq!(
+            parse_uri("compartya://open:my%20server/"),
+            open("my server")
+        );
+    }
+
+    #[test]
+    fn open_needs_a_server() {
+        for uri in [
+            "compartya://open:",
+            "compartya://open:/",
+            "compartya://open: /",
+        ] {
+            assert_eq!(parse_uri(uri), Err(ArgError::MissingServer), "{uri}");
+        }
+    }
+
+    #[test]
+    fn lobby_forms() {
+        for uri in [
+            "compartya://lobby/abcdefgh",
+            "compartya://lobby/abcdefgh/",
+            "compartya://lobby:abcdefgh",
+            "compartya://lobby:abcdefgh/",
+            "compartya:\\lobby:abcdefgh",
+        ] {
+            assert_eq!(parse_uri(uri), lobby("", None), "{uri}");
+        }
+    }
+
+    #[test]
+    fn lobby_query() {
+        assert_eq!(
+            parse_uri("compartya://lobby/abcdefgh/?invite=pa%26ss&server=1.2.3.4%3A5000"),
+            lobby("pa&ss", Some("1.2.3.4:5000"))
+        );
+        assert_eq!(
+            parse_uri("compartya://lobby:abcdefgh?server=1.2.3.4:5000&invite=pass"),
+            lobby("pass", Some("1.2.3.4:5000"))
+        );
+    }
+
+    #[test]
+    fn query_values_ignore_trailing_slashes() {
+        assert_eq!(
+            parse_uri("compartya://lobby/abcdefgh?server=1.2.3.4:5000&invite=pass/"),
+            lobby("pass", Some("1.2.3.4:5000"))
+        );
+        assert_eq!(
+            parse_uri("compartya://lobby/abcdefgh?invite=pass&server=1.2.3.4:5000/"),
+            lobby("pass", Some("1.2.3.4:5000"))
+        );
+    }
+
+    #[test]
+    fn lobby_errors() {
+        assert_eq!(
+            parse_uri("compartya://lobby/abc"),
+            Err(ArgError::InvalidLobby("abc".to_string()))
+        );
+        assert_eq!(
+            parse_uri("compartya://lobby/abcdefgh?invite=123456789"),
+            Err(ArgError::InvalidInvite)
+        );
+        assert_eq!(
+            parse_uri("compartya://lobby/abcdefgh?server=nowhere"),
+            Err(ArgError::InvalidServer("nowhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn bad_escapes() {
+        for uri in [
+            "compartya://open:%zz",
+            "compartya://open:abc%4",
+            "compartya://open:abc%",
+            "compartya://lobby/abcdefgh?invite=%G1",
+        ] {
+            assert!(
+                matches!(parse_uri(uri), Err(ArgError::BadEncoding(_))),
+                "{uri}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert!(matches!(
+            parse_uri("compartya://open:%FF%FE"),
+            Err(ArgError::BadEncoding(_))
+        ));
+        assert!(matches!(
+            parse_uri("compartya://lobby/abcdefgh?invite=%C3"),
+            Err(ArgError::BadEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_uris() {
+        assert_eq!(
+            parse_uri("https://open:abc"),
+            Err(ArgError::NotCompartya("https://open:abc".to_string()))
+        );
+        assert_eq!(
+            parse_uri("compartya://join:abc"),
+            Err(ArgError::UnknownAction("join".to_string()))
+        );
+        assert_eq!(
+            parse_uri("compartya://"),
+            Err(ArgError::UnknownAction(String::new()))
+        );
+    }
+
+    #[test]
+    fn launch_args() {
+        assert_eq!(
+            args(&[
+                "NorthstarLauncher.exe",
+                "-profile=R2Northstar",
+                IP_ARG,
+                "10.0.0.2",
+                PORT_ARG,
+                "4000",
+                SERVERS_ARG,
+                "a.example:1, b.example:2,",
+                "compartya://open:abc123/",
+            ]),
+            Ok(LaunchArgs {
+                ip: Some("10.0.0.2".parse().unwrap()),
+                port: Some(4000),
+                servers: vec!["a.example:1".to_string(), "b.example:2".to_string()],
+                uri: Some(CompartyaUri::OpenServer("abc123".to_string())),
+            })
+        );
+        assert_eq!(args(&["-multiple"]), Ok(LaunchArgs::default()));
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(args(&[IP_ARG]), Err(ArgError::MissingValue(IP_ARG)));
+        assert_eq!(
+            args(&["-multiple", PORT_ARG]),
+            Err(ArgError::MissingValue(PORT_ARG))
+        );
+        assert_eq!(
+            args(&[SERVERS_ARG]),
+            Err(ArgError::MissingValue(SERVERS_ARG))
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(
+            args(&[IP_ARG, "nowhere"]),
+            Err(ArgError::InvalidIp("nowhere".to_string()))
+        );
+        assert_eq!(
+            args(&[PORT_ARG, "70000"]),
+            Err(ArgError::InvalidPort("70000".to_string()))
+        );
+    }
+
+    #[test]
+    fn lobby_link_round_trips() {
+        let server = Some("1.2.3.4:5000".parse().unwrap());
+
+        for (password, server) in [
+            ("pass", server),
+            ("a&b=c d", server),
+            ("%/?#", None),
+            ("", server),
+            ("", None),
+        ] {
+            let link = lobby_link("abcdefgh", password, server);
+
+            assert_eq!(
+                parse_uri(&link),
+                Ok(CompartyaUri::JoinLobby(
+                    LOBBY,
+                    to_password(password).unwrap(),
+                    server
+                )),
+                "{link}"
+            );
+        }
+    }
+
+    #[test]
+    fn percent_round_trips() {
+        for value in ["plain", "a b&c=d", "ünïcödé", "%25"] {
+            assert_eq!(percent_decode(&percent_encode(value)).as_deref(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn passwords_are_padded() {
+        assert_eq!(
+            to_password("ab"),
+            Some(['a', 'b', ' ', ' ', ' ', ' ', ' ', ' '])
+        );
+        assert_eq!(
+            to_password("12345678"),
+            Some(['1', '2', '3', '4', '5', '6', '7', '8'])
+        );
+        assert_eq!(to_password("123456789"), None);
+    }
+
+    #[test]
+    fn host_addresses() {
+        assert_eq!(
+            parse_host_addr(" 10.0.0.2 "),
+            Some(SocketAddr::from(([10, 0, 0, 2], DEFAULT_PORT)))
+        );
+        assert_eq!(
+            parse_host_addr("10.0.0.2:4000"),
+            Some(SocketAddr::from(([10, 0, 0, 2], 4000)))
+        );
+        assert_eq!(parse_host_addr("nowhere"), None);
+    }
+}
@@ -1,22 +1,38 @@
 use bindings::{CmdSource, ECommandTarget, EngineFunctions, HostState, ENGINE_FUNCTIONS};
-use compartya_shared::{LobbyUid, Order, Password, PlayerUid};
+use compartya_shared::{
+    crypto::{KeyPair, SessionKey},
+    sanitize_name, Ban, LanAnnouncement, LobbyUid, MemberInfo, Order, OrderStatus, Password,
+    PlayerUid, RequiredMod, ResumeToken, ResumeVerifier, Roster, TransferToken,
+};
 use invite_handler::compartya_join_handler;
+use last_party::LastParty;
+use launch_args::{CompartyaUri, LaunchArgs};
+use link_stats::{LinkStats, LinkTracker};
+use nat::NatType;
+use orders::RequiredModInfo;
+use parking_lot::Mutex;
 use rrplug::{
     call_sq_function,
     exports::OnceCell,
     high::UnsafeHandle,
     mid::{
-        squirrel::SQVM_UI,
+        squirrel::{SQVM_CLIENT, SQVM_UI},
         utils::{set_c_char_array, to_cstring},
     },
     prelude::*,
 };
+use server_ranking::ServerCriteria;
+use settings::Settings;
 use std::{
     cell::RefCell,
+    collections::VecDeque,
     env,
-    net::SocketAddr,
-    process::Command,
-    sync::mpsc::{self, Receiver, Sender},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU16},
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Instant,
 };
 
 use crate::invite_handler::InviteHandler;
@@ -25,31 +41,82 @@ mod bindings;
 mod commands;
 mod gui;
 mod invite_handler;
+mod join_schedule;
+mod lan;
+mod last_party;
+mod launch_args;
+mod link_stats;
+mod local_addr;
+mod matchmaking;
+mod nat;
 mod networking;
 mod orders;
+mod server_ranking;
+mod settings;
+mod timers;
 mod urihandler;
 
-pub const MATCHMAKING_SERVER_ADDR: &str = env!(
-    "MATCHMAKING_SERVER_ADDR",
-    "provide the MATCHMAKING_SERVER_ADDR env var for the stun's server address : <ip:port>"
-);
+/// titanfall servers cap at 16 players
+pub const DEFAULT_PARTY_SIZE: u8 = 16;
 
 pub enum LocalMessage {
     ExecuteOrder(Order),
     ExecuteFunction(Box<dyn FnOnce() + Send>),
     ExecuteConCommand(String),
-    ConnectToLobby(LobbyUid, Password),
+    /// the matchmaking server that owns the lobby if we know it
+    ConnectToLobby(LobbyUid, Password, Option<SocketAddr>),
     BecomeHost(Password),
     BecomeUser,
     Leave,
     NewOrder(Order),
+    /// held until the party's mods are checked
+    StageOrder(Order),
+    ConfirmOrder,
+    OrderStaged(bool),
     GetCachedOrder,
     ForwardToGui(Box<LocalMessage>),
     ForwardToEngine(Box<LocalMessage>),
     LobbyUid(Option<String>),
     IsHost(bool),
-    NewConnection(String),
+    NewConnection(String, String),
     DroppedConnection(String),
+    ListMembers,
+    Reconnecting(bool),
+    GetLinkStats,
+    LinkStats(Vec<(String, LinkStats)>),
+    SendChat(String),
+    ChatMessage(String, String),
+    StartReadyCheck,
+    ReadyPrompt(Option<Instant>),
+    AnswerReady(bool),
+    ReadyTally(Vec<(String, Option<bool>)>, bool),
+    Kick(String, String, bool),
+    Notice(String),
+    SetPartySize(u8),
+    LockLobby(bool),
+    ReportOrderStatus(OrderStatus),
+    /// the id and name of the server we just got into
+    JoinedServer(String, String),
+    OrderStatuses(Vec<(String, OrderStatus)>),
+    CheckMods,
+    PreflightMods(Vec<RequiredMod>),
+    PreflightResult(Vec<String>),
+    ModCheckReport(Vec<(String, Option<Vec<String>>)>, bool),
+    PrioritizeJoin(Vec<String>),
+    FindServer(ServerCriteria),
+    /// the host's own search; the party follows once it joins what it found
+    SearchServers(ServerCriteria),
+    HasOrder(bool),
+    /// `None` picks the address automatically
+    BindTo(Option<Ipv4Addr>),
+    PartyLink(String),
+    RevokeInvite,
+    LanAnnounced(LanAnnouncement, SocketAddr),
+    /// skips the matchmaking server; the lobby id is only known for LAN parties
+    ConnectDirect(SocketAddr, Option<LobbyUid>, Password),
+    CheckNat,
+    NatType(NatType),
+    SettingsChanged(Settings),
 }
 
 #[derive(Debug)]
@@ -60,10 +127,61 @@ pub enum ConnectionState {
 
 #[derive(Default, Debug)]
 pub struct Host {
+    pub uid: PlayerUid,
     pub lobby_id: Option<LobbyUid>,
     pub password: Password,
-    pub clients: Vec<(SocketAddr, PlayerUid)>,
+    /// handed out in party links instead of the password; revoking it makes a new one
+    pub invite: Option<Password>,
+    pub clients: Vec<Client>,
+    pub suspended: Vec<(Client, Instant)>,
     pub last_order: Order,
+    pub stun_link: LinkTracker,
+    pub transfer_token: Option<TransferToken>,
+    /// the matchmaking server the lobby is registered with
+    pub matchmaking: Option<SocketAddr>,
+    pub ready_check: Option<ReadyCheck>,
+    pub bans: Vec<Ban>,
+    /// counts the host too
+    pub max_size: u8,
+    pub locked: bool,
+    pub keys: KeyPair,
+    pub mod_check: Option<ModCheck>,
+    /// the server the host joined, waiting on the mod check before the party follows
+    pub staged_order: Option<Order>,
+    /// who gets to join first when a server doesn't fit everyone
+    pub join_priority: Vec<PlayerUid>,
+    pub join_queue: VecDeque<PlayerUid>,
+    /// announced on the local network instead of registered with the matchmaking server
+    pub lan: bool,
+    /// wrong passwords by address, forgotten after a while
+    pub failed_auths: Vec<(IpAddr, Instant)>,
+}
+
+/// what each member is missing to join the current server; `None` until they answer
+#[derive(Debug)]
+pub struct ModCheck {
+    pub id: u32,
+    pub answers: Vec<(PlayerUid, Option<Vec<String>>)>,
+    pub open: bool,
+}
+
+#[derive(Debug)]
+pub struct ReadyCheck {
+    pub id: u32,
+    pub answers: Vec<(PlayerUid, Option<bool>)>,
+    pub open: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub addr: SocketAddr,
+    pub uid: PlayerUid,
+    pub resume_verifier: ResumeVerifier,
+    pub info: MemberInfo,
+    pub link: LinkTracker,
+    pub order_status: Option<OrderStatus>,
+    /// only missing for members taken over from a roster who didn't resume yet
+    pub session_key: Option<SessionKey>,
 }
 
 #[derive(Default, Debug)]
@@ -73,14 +191,104 @@ pub struct User {
     pub password: Password,
     pub cached_order: Order,
     pub connect_to: Option<SocketAddr>,
+    pub lobby_id: Option<LobbyUid>,
+    pub resume_token: Option<ResumeToken>,
+    pub reconnect_attempts: u32,
+    pub server_link: LinkTracker,
+    pub roster: Option<Box<Roster>>,
+    /// everyone else in the party as the host announced them
+    pub members: Vec<(PlayerUid, MemberInfo)>,
+    pub ready_prompt: Option<u32>,
+    /// whether the host should hear about how the last order is going
+    pub order_pending: bool,
+    pub keys: KeyPair,
+    pub session_key: Option<SessionKey>,
+    pub preflight: Option<u32>,
+    /// rejoining the party from before a restart; we don't know its password
+    pub restored: bool,
+    /// the host's address when we reach it without the matchmaking server
+    pub direct: Option<SocketAddr>,
+    /// what's saved on disk so it doesn't have to be read again to keep it fresh
+    pub last_party: Option<LastParty>,
+}
+
+impl User {
+    pub fn in_party(&self) -> bool {
+        self.lobby_id.is_some() || self.direct.is_some()
+    }
+}
+
+/// what scripts can ask about the party; follows the same messages the gui gets
+#[derive(Default, Debug)]
+pub struct PartyView {
+    pub is_host: bool,
+    pub lobby_id: Option<String>,
+    /// uid and name
+    pub members: Vec<(String, String)>,
+}
+
+impl PartyView {
+    fn observe(&mut self, msg: &LocalMessage) {
+        match msg {
+            LocalMessage::IsHost(hosting) => {
+                self.is_host = *hosting;
+                self.members.clear();
+            }
+            LocalMessage::LobbyUid(uid) => {
+                // leaving doesn't drop everyone one by one
+                if uid.is_none() {
+                    self.members.clear();
+                }
+
+                self.lobby_id = uid.clone()
+            }
+            LocalMessage::NewConnection(id, name) => {
+                match self.members.iter_mut().find(|(cmp_id, _)| cmp_id == id) {
+                    Some(member) => member.1 = name.clone(),
+                    None => self.members.push((id.clone(), name.clone())),
+                }
+            }
+            LocalMessage::DroppedConnection(id) => self.members.retain(|(cmp_id, _)| cmp_id != id),
+            _ => {}
+        }
+    }
+}
+
+/// a server password goes with the server it was entered for and no other
+#[derive(Debug, Clone)]
+pub struct ServerPassword {
+    /// `None` until we join a server with it
+    pub server: Option<String>,
+    pub password: String,
+}
+
+impl ServerPassword {
+    fn new(password: String) -> Option<Self> {
+        (!password.is_empty()).then_some(Self {
+            server: None,
+            password,
+        })
+    }
 }
 
 pub struct ComPartyaPlugin {
     recv_runframe: EngineGlobal<RefCell<Receiver<LocalMessage>>>,
-    send_runframe: Sender<LocalMessage>,
+    send_runframe: crossbeam_channel::Sender<LocalMessage>,
     send_gui: Sender<LocalMessage>,
     recv_gui: EngineGlobal<RefCell<Option<Receiver<LocalMessage>>>>,
     invite_handler: OnceCell<UnsafeHandle<&'static InviteHandler>>,
+    /// new lobbies are hosted on the local network only
+    lan_mode: AtomicBool,
+    /// the port parties reach us on
+    local_port: AtomicU16,
+    settings: Mutex<Settings>,
+    party: Mutex<PartyView>,
+    profile: Mutex<MemberInfo>,
+    /// the password of the server we are on or about to join
+    server_password: Mutex<Option<ServerPassword>>,
+    /// the order that brings the party to the server we are on
+    joined_server: Mutex<Option<Order>>,
+    server_search: Mutex<Option<ServerCriteria>>,
 }
 
 impl Plugin for ComPartyaPlugin {
@@ -109,52 +317,55 @@ impl Plugin for ComPartyaPlugin {
             Ok(_) => {}
         }
 
-        let (send_runframe, recv) = mpsc::channel();
+        let (send_runframe, recv) = crossbeam_channel::unbounded();
         let (send, recv_runframe) = mpsc::channel();
         let (send_gui, recv_gui) = mpsc::channel();
 
-        const IP_STRING: &str = "compartya_ip";
-        const PORT_STRING: &str = "compartya_port";
-
-        let args = env::args()
-            .zip(env::args().skip(1))
-            .filter(|(name, _)| name == IP_STRING || name == PORT_STRING)
-            .collect::<Vec<(String, String)>>();
-
-        const DEFAULT_PORT: &str = ":12352";
+        let mut launch =
+            launch_args::parse_launch_args(env::args().skip(1)).unwrap_or_else(|err| {
+                log::error!("couldn't read the launch arguments: {err}");
+                LaunchArgs::default()
+            });
+
+        log::info!("collected {launch:#?}\n real {:#?}", env::args());
+
+        let settings = settings::load();
+        let servers = matchmaking::server_list(&launch.servers, &settings.matchmaking_servers);
+        let local_port = launch.port.unwrap_or(settings.port);
+        let addr = SocketAddr::new(
+            launch.ip.unwrap_or_else(|| {
+                local_addr::local_ip(servers.iter().find_map(|server| server.parse().ok())).into()
+            }),
+            local_port,
+        )
+        .to_string();
+
+        let (local_order, local_lobby) = match launch.uri.take() {
+            Some(CompartyaUri::OpenServer(server)) => (Some(Order::join_server(server)), None),
+            Some(CompartyaUri::JoinLobby(lobby_id, password, server)) => {
+                (None, Some((lobby_id, password, server)))
+            }
+            None => (None, None),
+        };
 
-        log::info!("collected {args:#?}\n real {:#?}", env::args());
+        // a party link wins over rejoining the last party
+        let last_party = local_lobby.is_none().then(last_party::load).flatten();
 
-        let addr = args
-            .iter()
-            .find(|(name, _)| name == IP_STRING)
-            .map(|(_, arg)| arg.to_owned())
-            .unwrap_or_else(get_local_ip)
-            + &args
-                .iter()
-                .find(|(name, _)| name == PORT_STRING)
-                .map(|(_, arg)| ":".to_string() + arg)
-                .unwrap_or(DEFAULT_PORT.to_string());
-
-        let local_order = env::args().find_map(|arg| {
-            arg.starts_with("compartya::%5Copen:")
-                .then(|| arg.split_once("open:").map(|server_id| server_id.1))
-                .flatten()
-                .map(|server_id| Order::JoinServer(server_id.to_string(), String::new()))
-                .or_else(|| {
-                    arg.starts_with("compartya://open:")
-                        .then(|| {
-                            arg.split_once("open:")
-                                .map(|server_id| &server_id.1[0..server_id.1.len() - 1])
-                        })
-                        .flatten()
-                        .map(|server_id| Order::JoinServer(server_id.to_string(), String::new()))
-                })
-        });
+        if let Some((lobby_id, password, server)) = local_lobby {
+            _ = send_runframe.send(LocalMessage::ConnectToLobby(lobby_id, password, server));
+        }
 
         std::thread::spawn(move || {
-            _ = networking::run_connections(recv, send, addr, local_order)
-                .map_err(|err| log::error!("{err}"))
+            _ = networking::run_connections(
+                recv,
+                send,
+                addr,
+                launch,
+                servers,
+                local_order,
+                last_party,
+            )
+            .map_err(|err| log::error!("{err}"))
         });
 
         Self {
@@ -163,6 +374,14 @@ impl Plugin for ComPartyaPlugin {
             send_gui,
             recv_gui: EngineGlobal::new(RefCell::new(Some(recv_gui))),
             invite_handler: OnceCell::new(),
+            lan_mode: AtomicBool::new(false),
+            local_port: AtomicU16::new(local_port),
+            settings: Mutex::new(settings),
+            party: Mutex::new(PartyView::default()),
+            profile: Mutex::new(MemberInfo::default()),
+            server_password: Mutex::new(None),
+            joined_server: Mutex::new(None),
+            server_search: Mutex::new(None),
         }
     }
 
@@ -193,7 +412,7 @@ impl Plugin for ComPartyaPlugin {
             Err(_) => log::warn!("invite handler doesn't exist"),
         }
 
-        commands::create_commands(engine_data, engine_token)
+        commands::create_commands(engine_data, engine_token, &self.settings.lock())
     }
 
     fn runframe(&self, engine_token: EngineToken) {
@@ -223,13 +442,22 @@ impl Plugin for ComPartyaPlugin {
 
         match recved {
             LocalMessage::ExecuteOrder(order) => match order {
-                Order::JoinServer(id, password) => {
+                Order::JoinServer {
+                    id,
+                    password,
+                    required_mods,
+                    ..
+                } => {
                     _ = call_sq_function!(
                         SQVM_UI.get(engine_token).borrow().expect("should be init"),
                         SQFUNCTIONS.client.wait(),
                         "CompartyaJoinServer",
                         id,
-                        password
+                        password,
+                        required_mods
+                            .into_iter()
+                            .map(RequiredModInfo::from)
+                            .collect::<Vec<_>>()
                     )
                     .map_err(|err| err.log());
                 } //compartya::\open:f4bffec013fe65b634ba2ea499a86fa3
@@ -242,9 +470,66 @@ impl Plugin for ComPartyaPlugin {
                     host_state.next_state = HostState::NewGame;
                     set_c_char_array(&mut host_state.level_name, "mp_lobby");
                 }
+                Order::NoOrder => match self.settings.lock().fallback_server().map(String::from) {
+                    Some(id) => {
+                        log::info!("no order from the host yet; going to the fallback server");
+
+                        _ = call_sq_function!(
+                            SQVM_UI.get(engine_token).borrow().expect("should be init"),
+                            SQFUNCTIONS.client.wait(),
+                            "CompartyaJoinServer",
+                            id,
+                            String::new(),
+                            Vec::<RequiredModInfo>::new()
+                        )
+                        .map_err(|err| err.log());
+                    }
+                    None => log::info!("the host hasn't given an order yet"),
+                },
             },
+            LocalMessage::PreflightMods(required_mods) => {
+                _ = call_sq_function!(
+                    SQVM_UI.get(engine_token).borrow().expect("should be init"),
+                    SQFUNCTIONS.client.wait(),
+                    "CompartyaPreflightMods",
+                    required_mods
+                        .into_iter()
+                        .map(RequiredModInfo::from)
+                        .collect::<Vec<_>>()
+                )
+                .map_err(|err| err.log());
+            }
+            LocalMessage::SearchServers(criteria) => {
+                *self.server_search.lock() = Some(criteria);
+
+                _ = call_sq_function!(
+                    SQVM_UI.get(engine_token).borrow().expect("should be init"),
+                    SQFUNCTIONS.client.wait(),
+                    "CompartyaFetchServers"
+                )
+                .map_err(|err| err.log());
+            }
             LocalMessage::ExecuteFunction(func) => func(),
-            LocalMessage::ForwardToGui(msg) => _ = self.send_gui.send(*msg),
+            LocalMessage::ForwardToGui(msg) => {
+                self.party.lock().observe(&msg);
+                _ = self.send_gui.send(*msg)
+            }
+            LocalMessage::ChatMessage(sender, text) => {
+                if self.settings.lock().mirror_chat {
+                    if let Some(sqvm) = *SQVM_CLIENT.get(engine_token).borrow() {
+                        _ = call_sq_function!(
+                            sqvm,
+                            SQFUNCTIONS.client.wait(),
+                            "CompartyaPrintChat",
+                            sender.clone(),
+                            text.clone()
+                        )
+                        .map_err(|err| err.log());
+                    }
+                }
+
+                _ = self.send_gui.send(LocalMessage::ChatMessage(sender, text));
+            }
             LocalMessage::ExecuteConCommand(cmd) => {
                 let cmd = to_cstring(&cmd);
 
@@ -263,6 +548,15 @@ impl Plugin for ComPartyaPlugin {
     fn on_sqvm_created(&self, sqvm_handle: &CSquirrelVMHandle, engine_token: EngineToken) {
         orders::init_order_capture(sqvm_handle, engine_token);
 
+        if sqvm_handle.get_context() == ScriptContext::UI {
+            if let Some(name) = ConVarStruct::find_convar_by_name("name", engine_token)
+                .ok()
+                .and_then(|cvar| sanitize_name(&cvar.get_value_string()))
+            {
+                self.profile.lock().name = name;
+            }
+        }
+
         if sqvm_handle.get_context() == ScriptContext::CLIENT {
             _ = self
                 .recv_gui
@@ -274,29 +568,26 @@ impl Plugin for ComPartyaPlugin {
     }
 }
 
-fn get_local_ip() -> String {
-    let cmd_result = Command::new("ipconfig")
-        .output()
-        .expect("failed to get ipconfig; consider looking at the readme")
-        .stdout;
-    let cmd_result = String::from_utf8_lossy(&cmd_result);
-    cmd_result
-        .split('\n')
-        .filter(|line| line.contains("IPv4 Address"))
-        .filter_map(|line| line.split(':').nth(1))
-        .map(|addr| addr.trim().trim_end())
-        .last()
-        .or_else(|| {
-            // redo the operation with a more lax parsing
-            cmd_result
-                .split('\n')
-                .filter(|line| line.to_uppercase().contains("IPV4"))
-                .filter_map(|line| line.split(':').nth(1))
-                .map(|addr| addr.trim().trim_end())
-                .last()
-        })
-        .expect("couldn't find the machine's ip address; consider looking at the readme")
-        .to_string()
-}
-
 entry!(ComPartyaPlugin);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaving_forgets_the_members() {
+        let mut party = PartyView::default();
+
+        party.observe(&LocalMessage::LobbyUid(Some("abcdefgh".to_string())));
+        party.observe(&LocalMessage::NewConnection(
+            "abcde".to_string(),
+            "member".to_string(),
+        ));
+        assert_eq!(party.members.len(), 1);
+
+        party.observe(&LocalMessage::LobbyUid(None));
+
+        assert_eq!(party.lobby_id, None);
+        assert!(party.members.is_empty());
+    }
+}
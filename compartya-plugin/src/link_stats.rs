@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// a ping without a pong after this long counts as lost
+const LOSS_TIMEOUT: Duration = Duration::from_secs(2);
+const LOSS_WINDOW: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    pub rtt: Option<Duration>,
+    pub jitter: Duration,
+    /// 0.0 to 1.0 over the last few pings
+    pub loss: f32,
+}
+
+/// keeps track of the pings sent to one peer; rtt and jitter are smoothed like tcp's srtt and rtp's jitter
+#[derive(Debug, Clone, Default)]
+pub struct LinkTracker {
+    next_seq: u32,
+    in_flight: VecDeque<(u32, Instant)>,
+    outcomes: VecDeque<bool>,
+    srtt: Option<Duration>,
+    last_sample: Option<Duration>,
+    jitter: Duration,
+}
+
+impl LinkTracker {
+    pub fn next_ping(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.in_flight.push_back((seq, now));
+
+        seq
+    }
+
+    pub fn on_pong(&mut self, seq: u32, now: Instant) {
+        let Some(i) = self.in_flight.iter().position(|(s, _)| *s == seq) else {
+            return; // already counted as lost or a duplicate
+        };
+        let (_, sent_at) = self
+            .in_flight
+            .remove(i)
+            .expect("index was just found in the queue");
+        let sample = now.saturating_duration_since(sent_at);
+
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+
+        if let Some(last) = self.last_sample {
+            let delta = sample.abs_diff(last);
+
+            self.jitter = if delta > self.jitter {
+                self.jitter + (delta - self.jitter) / 16
+            } else {
+                self.jitter - (self.jitter - delta) / 16
+            };
+        }
+        self.last_sample = Some(sample);
+
+        self.record(true);
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        let lost = self.outcomes.iter().filter(|received| !**received).count();
+
+        LinkStats {
+            rtt: self.srtt,
+            jitter: self.jitter,
+            loss: if self.outcomes.is_empty() {
+                0.
+            } else {
+                lost as f32 / self.outcomes.len() as f32
+            },
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, sent_at)) = self.in_flight.front() {
+            if now.saturating_duration_since(*sent_at) < LOSS_TIMEOUT {
+                break;
+            }
+
+            self.in_flight.pop_front();
+            self.record(false);
+        }
+    }
+
+    fn record(&mut self, received: bool) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(received);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// one ping every 100ms answered after `rtt`
+    fn answer(tracker: &mut LinkTracker, start: Instant, rtts: &[Duration]) -> Instant {
+        let mut now = start;
+
+        for rtt in rtts {
+            let seq = tracker.next_ping(now);
+            tracker.on_pong(seq, now + *rtt);
+            now += 100 * MS;
+        }
+
+        now
+    }
+
+    #[test]
+    fn nothing_sent_is_no_stats() {
+        assert_eq!(LinkTracker::default().stats(), LinkStats::default());
+    }
+
+    #[test]
+    fn first_pong_is_the_rtt() {
+        let mut tracker = LinkTracker::default();
+        answer(&mut tracker, Instant::now(), &[40 * MS]);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.rtt, Some(40 * MS));
+        assert_eq!(stats.jitter, Duration::ZERO);
+        assert_eq!(stats.loss, 0.);
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut tracker = LinkTracker::default();
+        answer(&mut tracker, Instant::now(), &[40 * MS, 120 * MS]);
+
+        // 7/8 of the old estimate and 1/8 of the new sample
+        assert_eq!(tracker.stats().rtt, Some(50 * MS));
+    }
+
+    #[test]
+    fn steady_rtt_has_no_jitter() {
+        let mut tracker = LinkTracker::default();
+        answer(&mut tracker, Instant::now(), &[30 * MS; 10]);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.rtt, Some(30 * MS));
+        assert_eq!(stats.jitter, Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_follows_rtt_changes() {
+        let mut tracker = LinkTracker::default();
+        answer(&mut tracker, Instant::now(), &[20 * MS, 36 * MS]);
+
+        // a 16ms jump moves the jitter by 1/16 of it
+        assert_eq!(tracker.stats().jitter, MS);
+
+        answer(&mut tracker, Instant::now(), &[36 * MS]);
+        assert_eq!(tracker.stats().jitter, MS - MS / 16);
+    }
+
+    #[test]
+    fn unanswered_pings_are_lost() {
+        let mut tracker = LinkTracker::default();
+        let start = Instant::now();
+
+        tracker.next_ping(start);
+        tracker.next_ping(start);
+        assert_eq!(tracker.stats().loss, 0., "not lost until the timeout");
+
+        let later = answer(&mut tracker, start + LOSS_TIMEOUT, &[10 * MS, 10 * MS]);
+
+        assert_eq!(tracker.stats().loss, 0.5);
+        assert_eq!(tracker.stats().rtt, Some(10 * MS));
+
+        // the loss only looks at the last few pings
+        answer(&mut tracker, later, &[10 * MS; LOSS_WINDOW]);
+        assert_eq!(tracker.stats().loss, 0.);
+    }
+
+    #[test]
+    fn late_and_duplicate_pongs_are_ignored() {
+        let mut tracker = LinkTracker::default();
+        let start = Instant::now();
+
+        let late = tracker.next_ping(start);
+        let answered = answer(&mut tracker, start + LOSS_TIMEOUT, &[10 * MS]) - 100 * MS;
+        let stats = tracker.stats();
+
+        // already counted as lost
+        tracker.on_pong(late, answered + 10 * MS);
+        // answered a second time
+        tracker.on_pong(late.wrapping_add(1), answered + 500 * MS);
+        // never sent
+        tracker.on_pong(999, answered);
+
+        assert_eq!(tracker.stats(), stats);
+        assert_eq!(stats.loss, 0.5);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut tracker = LinkTracker {
+            next_seq: u32::MAX - 1,
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        let seqs = [
+            tracker.next_ping(now),
+            tracker.next_ping(now),
+            tracker.next_ping(now),
+        ];
+        assert_eq!(seqs, [u32::MAX - 1, u32::MAX, 0]);
+
+        for seq in seqs.into_iter().rev() {
+            tracker.on_pong(seq, now + 20 * MS);
+        }
+
+        let stats = tracker.stats();
+        assert_eq!(stats.rtt, Some(20 * MS));
+        assert_eq!(stats.loss, 0.);
+    }
+}
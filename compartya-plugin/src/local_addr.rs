@@ -0,0 +1,148 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+/// a local ipv4 address we could listen on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+}
+
+pub fn interfaces() -> Vec<Interface> {
+    if_addrs::get_if_addrs()
+        .map_err(|err| log::warn!("couldn't list the network interfaces: {err}"))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|interface| match interface.ip() {
+            IpAddr::V4(ip) => Some(Interface {
+                name: interface.name,
+                ip,
+            }),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// the address the os would send from to reach `target`; connecting a udp socket doesn't send anything
+pub fn route_ip(target: SocketAddr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(target).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// the routed address wins, then lan addresses, then anything that isn't loopback
+pub fn pick_local_ip(route: Option<Ipv4Addr>, interfaces: &[Interface]) -> Option<Ipv4Addr> {
+    let usable = |ip: &Ipv4Addr| !ip.is_loopback() && !ip.is_unspecified() && !ip.is_link_local();
+
+    route
+        .filter(usable)
+        .or_else(|| {
+            interfaces
+                .iter()
+                .map(|interface| interface.ip)
+                .find(|ip| usable(ip) && ip.is_private())
+        })
+        .or_else(|| interfaces.iter().map(|interface| interface.ip).find(usable))
+}
+
+/// listens on every interface if nothing better turns up
+pub fn local_ip(target: Option<SocketAddr>) -> Ipv4Addr {
+    pick_local_ip(target.and_then(route_ip), &interfaces()).unwrap_or_else(|| {
+        log::warn!("couldn't find the machine's ip address; listening on every interface");
+        Ipv4Addr::UNSPECIFIED
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(ip: [u8; 4]) -> Interface {
+        Interface {
+            name: format!("if{}", ip[3]),
+            ip: Ipv4Addr::from(ip),
+        }
+    }
+
+    #[test]
+    fn route_wins() {
+        let interfaces = [interface([192, 168, 1, 2]), interface([10, 0, 0, 2])];
+
+        assert_eq!(
+            pick_local_ip(Some(Ipv4Addr::new(10, 0, 0, 2)), &interfaces),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        // even if no interface claims it
+        assert_eq!(
+            pick_local_ip(Some(Ipv4Addr::new(172, 16, 0, 9)), &interfaces),
+            Some(Ipv4Addr::new(172, 16, 0, 9))
+        );
+    }
+
+    #[test]
+    fn unusable_routes_are_ignored() {
+        let interfaces = [interface([192, 168, 1, 2])];
+
+        for route in [
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(169, 254, 3, 4),
+        ] {
+            assert_eq!(
+                pick_local_ip(Some(route), &interfaces),
+                Some(Ipv4Addr::new(192, 168, 1, 2)),
+                "{route} shouldn't be picked"
+            );
+        }
+    }
+
+    #[test]
+    fn unusable_interfaces_are_skipped() {
+        let interfaces = [
+            interface([127, 0, 0, 1]),
+            interface([0, 0, 0, 0]),
+            interface([169, 254, 3, 4]),
+            interface([192, 168, 1, 2]),
+        ];
+
+        assert_eq!(
+            pick_local_ip(None, &interfaces),
+            Some(Ipv4Addr::new(192, 168, 1, 2))
+        );
+    }
+
+    #[test]
+    fn private_before_public() {
+        let interfaces = [interface([203, 0, 113, 7]), interface([10, 1, 2, 3])];
+
+        assert_eq!(
+            pick_local_ip(None, &interfaces),
+            Some(Ipv4Addr::new(10, 1, 2, 3))
+        );
+    }
+
+    #[test]
+    fn public_if_nothing_else() {
+        let interfaces = [interface([127, 0, 0, 1]), interface([203, 0, 113, 7])];
+
+        assert_eq!(
+            pick_local_ip(None, &interfaces),
+            Some(Ipv4Addr::new(203, 0, 113, 7))
+        );
+    }
+
+    #[test]
+    fn nothing_usable() {
+        assert_eq!(pick_local_ip(None, &[]), None);
+        assert_eq!(
+            pick_local_ip(
+                Some(Ipv4Addr::LOCALHOST),
+                &[interface([127, 0, 0, 1]), interface([169, 254, 0, 1])]
+            ),
+            None
+        );
+    }
+}
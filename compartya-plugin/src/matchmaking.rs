@@ -0,0 +1,68 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// what compartya-server listens on unless told otherwise
+pub const DEFAULT_SERVER_PORT: u16 = 2000;
+/// baked in at build time as the last resort
+const BUILTIN_SERVER: Option<&str> = option_env!("MATCHMAKING_SERVER_ADDR");
+
+/// launch arguments go first, then the settings, then the server the plugin was built with
+pub fn server_list(from_args: &[String], from_settings: &[String]) -> Vec<String> {
+    let mut servers = Vec::new();
+
+    for server in from_args
+        .iter()
+        .chain(from_settings)
+        .map(String::as_str)
+        .chain(BUILTIN_SERVER)
+    {
+        let server = server.trim().to_string();
+
+        if !server.is_empty() && !servers.contains(&server) {
+            servers.push(server);
+        }
+    }
+
+    servers
+}
+
+/// `host:port` or just the host for servers on the default port; hostnames get looked up
+pub fn resolve(server: &str) -> Option<SocketAddr> {
+    let server = server.trim();
+    let has_port = server
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+
+    let server = if has_port {
+        server.to_string()
+    } else {
+        format!("{server}:{DEFAULT_SERVER_PORT}")
+    };
+
+    server.to_socket_addrs().ok()?.find(SocketAddr::is_ipv4)
+}
+
+pub fn resolve_all(servers: &[String]) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+
+    for server in servers {
+        match resolve(server) {
+            Some(addr) if !resolved.contains(&addr) => resolved.push(addr),
+            Some(_) => {}
+            None => log::warn!("couldn't find the matchmaking server {server}"),
+        }
+    }
+
+    resolved
+}
+
+/// discord and `p_connect_to_lobby` carry the lobby's server along with its id: `<lobby id>@<server>`
+pub fn lobby_secret(lobby_id: &str, server: SocketAddr) -> String {
+    format!("{lobby_id}@{server}")
+}
+
+pub fn split_lobby_secret(secret: &str) -> (&str, Option<SocketAddr>) {
+    match secret.split_once('@') {
+        Some((lobby_id, server)) => (lobby_id, server.parse().ok()),
+        None => (secret, None),
+    }
+}
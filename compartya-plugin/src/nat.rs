@@ -0,0 +1,417 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+/// how long each step of the check waits for the matchmaking server to answer
+pub const NAT_PROBE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// the server sees the address we bound to
+    Open,
+    /// telling full and restricted cones apart needs a second server ip
+    Cone,
+    PortRestricted,
+    Symmetric,
+    /// the matchmaking server never answered
+    Blocked,
+}
+
+impl NatType {
+    /// members punch through to the address the matchmaking server saw, a new mapping per peer breaks that
+    pub fn hole_punching_unlikely(self) -> bool {
+        matches!(self, NatType::Symmetric | NatType::Blocked)
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NatType::Open => "open",
+            NatType::Cone => "full or restricted cone",
+            NatType::PortRestricted => "port restricted",
+            NatType::Symmetric => "symmetric",
+            NatType::Blocked => "blocked",
+        })
+    }
+}
+
+/// what the matchmaking server's two ports saw of us during one check
+#[derive(Debug, Default)]
+pub struct NatProbe {
+    pub id: u32,
+    pub local: Option<SocketAddr>,
+    pub primary: Option<SocketAddr>,
+    pub secondary: Option<SocketAddr>,
+    pub probe_port: Option<u16>,
+    /// the probe port got through before we ever sent anything to it
+    pub unsolicited: bool,
+    pub asked_secondary: bool,
+}
+
+impl NatProbe {
+    pub fn new(id: u32, local: SocketAddr) -> Self {
+        Self {
+            id,
+            local: Some(local),
+            ..Default::default()
+        }
+    }
+
+    /// answers only count if they come from the server's ip
+    pub fn record(
+        &mut self,
+        from: SocketAddr,
+        server: SocketAddr,
+        id: u32,
+        mapped: SocketAddr,
+        probe_port: u16,
+    ) {
+        if id != self.id || from.ip() != server.ip() {
+            return;
+        }
+
+        if from.port() == server.port() {
+            self.primary = Some(mapped);
+            self.probe_port = Some(probe_port);
+        } else if from.port() == probe_port {
+            if self.asked_secondary {
+                self.secondary = Some(mapped);
+            } else {
+                self.unsolicited = true;
+            }
+        }
+    }
+
+    pub fn classify(&self) -> NatType {
+        let Some(primary) = self.primary else {
+            return NatType::Blocked;
+        };
+
+        if self.local == Some(primary) {
+            return NatType::Open;
+        }
+
+        match self.secondary {
+            Some(secondary) if secondary != primary => NatType::Symmetric,
+            _ if self.unsolicited => NatType::Cone,
+            _ => NatType::PortRestricted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::{HashMap, HashSet},
+        net::UdpSocket,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const ID: u32 = 7;
+    const SERVER: &str = "203.0.113.1:12351";
+    const PROBE_PORT: u16 = 12360;
+    const LOCAL: &str = "192.168.1.2:12352";
+    const MAPPED: &str = "198.51.100.7:40000";
+    /// how long the simulated check waits for each answer
+    const STEP_WAIT: Duration = Duration::from_millis(300);
+    /// stops the stub and relay threads once a test is done with them
+    const THREAD_IDLE: Duration = Duration::from_secs(5);
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn probe() -> NatProbe {
+        NatProbe::new(ID, addr(LOCAL))
+    }
+
+    fn from_probe_port() -> SocketAddr {
+        SocketAddr::new(addr(SERVER).ip(), PROBE_PORT)
+    }
+
+    #[test]
+    fn no_answer_is_blocked() {
+        assert_eq!(probe().classify(), NatType::Blocked);
+    }
+
+    #[test]
+    fn unchanged_address_is_open() {
+        let mut probe = probe();
+        probe.record(addr(SERVER), addr(SERVER), ID, addr(LOCAL), PROBE_PORT);
+
+        assert_eq!(probe.classify(), NatType::Open);
+    }
+
+    #[test]
+    fn unsolicited_probe_is_cone() {
+        let mut probe = probe();
+        probe.record(addr(SERVER), addr(SERVER), ID, addr(MAPPED), PROBE_PORT);
+        probe.record(
+            from_probe_port(),
+            addr(SERVER),
+            ID,
+            addr(MAPPED),
+            PROBE_PORT,
+        );
+
+        assert!(probe.unsolicited);
+        assert_eq!(probe.classify(), NatType::Cone);
+    }
+
+    #[test]
+    fn same_mapping_is_port_restricted() {
+        let mut probe = probe();
+        probe.record(addr(SERVER), addr(SERVER), ID, addr(MAPPED), PROBE_PORT);
+        assert_eq!(probe.classify(), NatType::PortRestricted);
+
+        probe.asked_secondary = true;
+        probe.record(
+            from_probe_port(),
+            addr(SERVER),
+            ID,
+            addr(MAPPED),
+            PROBE_PORT,
+        );
+
+        assert!(!probe.unsolicited);
+        assert_eq!(probe.classify(), NatType::PortRestricted);
+    }
+
+    #[test]
+    fn new_mapping_is_symmetric() {
+        let mut probe = probe();
+        probe.record(addr(SERVER), addr(SERVER), ID, addr(MAPPED), PROBE_PORT);
+        probe.asked_secondary = true;
+        probe.record(
+            from_probe_port(),
+            addr(SERVER),
+            ID,
+            addr("198.51.100.7:40001"),
+            PROBE_PORT,
+        );
+
+        assert_eq!(probe.classify(), NatType::Symmetric);
+        assert!(probe.classify().hole_punching_unlikely());
+    }
+
+    #[test]
+    fn record_ignores_other_checks() {
+        let mut probe = probe();
+        probe.record(addr(SERVER), addr(SERVER), ID - 1, addr(MAPPED), PROBE_PORT);
+
+        assert_eq!(probe.primary, None);
+        assert_eq!(probe.classify(), NatType::Blocked);
+    }
+
+    #[test]
+    fn record_ignores_other_ips() {
+        let mut probe = probe();
+        probe.record(
+            addr("198.51.100.9:12351"),
+            addr(SERVER),
+            ID,
+            addr(MAPPED),
+            PROBE_PORT,
+        );
+        assert_eq!(probe.primary, None);
+
+        probe.record(addr(SERVER), addr(SERVER), ID, addr(MAPPED), PROBE_PORT);
+        probe.record(
+            SocketAddr::new(addr("198.51.100.9:0").ip(), PROBE_PORT),
+            addr(SERVER),
+            ID,
+            addr(MAPPED),
+            PROBE_PORT,
+        );
+        assert!(!probe.unsolicited);
+
+        // the server's ip on some other port isn't the probe port
+        probe.asked_secondary = true;
+        probe.record(
+            SocketAddr::new(addr(SERVER).ip(), 9999),
+            addr(SERVER),
+            ID,
+            addr("198.51.100.7:40001"),
+            PROBE_PORT,
+        );
+        assert_eq!(probe.secondary, None);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Nat {
+        FullCone,
+        RestrictedCone,
+        PortRestricted,
+        Symmetric,
+    }
+
+    /// answers probes like the matchmaking server; `id other_port` in, `id mapped probe_port` out
+    fn stub_server() -> SocketAddr {
+        let primary = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let probe = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let probe_port = probe.local_addr().unwrap().port();
+        let server = primary.local_addr().unwrap();
+        let other = probe.try_clone().unwrap();
+
+        for (socket, other) in [(primary, Some(other)), (probe, None)] {
+            socket.set_read_timeout(Some(THREAD_IDLE)).unwrap();
+
+            thread::spawn(move || {
+                let mut buf = [0; 128];
+
+                while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                    let request = std::str::from_utf8(&buf[..len]).unwrap();
+                    let (id, other_port) = request.split_once(' ').unwrap();
+                    let answer = format!("{id} {from} {probe_port}");
+
+                    if let (Some(other), "true") = (other.as_ref(), other_port) {
+                        _ = other.send_to(answer.as_bytes(), from);
+                    }
+                    _ = socket.send_to(answer.as_bytes(), from);
+                }
+            });
+        }
+
+        server
+    }
+
+    /// a nat in front of `client`; it prefixes what it sends with the destination and gets
+    /// back what got through prefixed with where it came from
+    fn relay(nat: Nat, client: SocketAddr) -> SocketAddr {
+        let inside = UdpSocket::bind("127.0.0.1:0").unwrap();
+        inside.set_read_timeout(Some(THREAD_IDLE)).unwrap();
+        let relay = inside.local_addr().unwrap();
+        let contacted = Arc::new(Mutex::new(HashSet::<SocketAddr>::new()));
+
+        thread::spawn(move || {
+            let mut mappings = HashMap::new();
+            let mut buf = [0; 128];
+
+            while let Ok(len) = inside.recv(&mut buf) {
+                let packet = std::str::from_utf8(&buf[..len]).unwrap();
+                let (dest, payload) = packet.split_once(' ').unwrap();
+                let dest = addr(dest);
+                contacted.lock().unwrap().insert(dest);
+
+                // a symmetric nat maps each destination to its own port
+                let outside = mappings
+                    .entry((nat == Nat::Symmetric).then_some(dest))
+                    .or_insert_with(|| {
+                        let outside = UdpSocket::bind("127.0.0.1:0").unwrap();
+                        outside.set_read_timeout(Some(THREAD_IDLE)).unwrap();
+                        let back = (outside.try_clone().unwrap(), inside.try_clone().unwrap());
+                        let contacted = contacted.clone();
+
+                        thread::spawn(move || {
+                            let (outside, inside) = back;
+                            let mut buf = [0; 128];
+
+                            while let Ok((len, from)) = outside.recv_from(&mut buf) {
+                                let contacted = contacted.lock().unwrap();
+                                let let_through = match nat {
+                                    Nat::FullCone => true,
+                                    Nat::RestrictedCone => {
+                                        contacted.iter().any(|to| to.ip() == from.ip())
+                                    }
+                                    Nat::PortRestricted | Nat::Symmetric => {
+                                        contacted.contains(&from)
+                                    }
+                                };
+
+                                if let_through {
+                                    let packet = std::str::from_utf8(&buf[..len]).unwrap();
+                                    _ = inside
+                                        .send_to(format!("{from} {packet}").as_bytes(), client);
+                                }
+                            }
+                        });
+
+                        outside
+                    });
+
+                _ = outside.send_to(payload.as_bytes(), dest);
+            }
+        });
+
+        relay
+    }
+
+    /// runs the check the way the networking loop does, through `nat` if there is one
+    fn check(nat: Option<Nat>, server: SocketAddr) -> NatType {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(STEP_WAIT)).unwrap();
+        let local = client.local_addr().unwrap();
+        let relay = nat.map(|nat| relay(nat, local));
+
+        let send = |dest: SocketAddr, request: String| {
+            match relay {
+                Some(relay) => client.send_to(format!("{dest} {request}").as_bytes(), relay),
+                None => client.send_to(request.as_bytes(), dest),
+            }
+            .unwrap();
+        };
+        let receive = |probe: &mut NatProbe| {
+            let mut buf = [0; 128];
+
+            while let Ok((len, from)) = client.recv_from(&mut buf) {
+                let packet = std::str::from_utf8(&buf[..len]).unwrap();
+                let (from, answer) = match relay {
+                    Some(_) => {
+                        let (from, answer) = packet.split_once(' ').unwrap();
+                        (addr(from), answer)
+                    }
+                    None => (from, packet),
+                };
+                let answer = answer.split(' ').collect::<Vec<&str>>();
+
+                probe.record(
+                    from,
+                    server,
+                    answer[0].parse().unwrap(),
+                    addr(answer[1]),
+                    answer[2].parse().unwrap(),
+                );
+            }
+        };
+
+        let mut probe = NatProbe::new(ID, local);
+        send(server, format!("{ID} true"));
+        receive(&mut probe);
+
+        if let Some(probe_port) = probe.probe_port {
+            probe.asked_secondary = true;
+            send(
+                SocketAddr::new(server.ip(), probe_port),
+                format!("{ID} false"),
+            );
+            receive(&mut probe);
+        }
+
+        probe.classify()
+    }
+
+    #[test]
+    fn checks_through_a_relay() {
+        let server = stub_server();
+
+        assert_eq!(check(None, server), NatType::Open);
+        assert_eq!(check(Some(Nat::FullCone), server), NatType::Cone);
+        assert_eq!(check(Some(Nat::RestrictedCone), server), NatType::Cone);
+        assert_eq!(
+            check(Some(Nat::PortRestricted), server),
+            NatType::PortRestricted
+        );
+        assert_eq!(check(Some(Nat::Symmetric), server), NatType::Symmetric);
+    }
+
+    #[test]
+    fn silent_server_is_blocked() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        assert_eq!(
+            check(Some(Nat::PortRestricted), silent.local_addr().unwrap()),
+            NatType::Blocked
+        );
+    }
+}
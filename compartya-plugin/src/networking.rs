@@ -1,18 +1,57 @@
-use compartya_shared::{Order, PacketMessage, PacketResponse, PartyaError, PlayerUid, SentPacket};
+use compartya_shared::{
+    crypto::resume_verifier, AuthRejection, Ban, LanAnnouncement, LobbySettings, LobbyUid,
+    MemberInfo, Order, OrderStatus, PacketMessage, PacketResponse, PartyaError, PlayerUid,
+    RequiredMod, Roster, RosterMember, SentPacket, MAX_CHAT_LEN,
+};
 use laminar::{ErrorKind, Packet, Socket, SocketEvent};
 use rrplug::prelude::*;
 use std::{
-    net::SocketAddr,
-    sync::mpsc::{Receiver, Sender},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    join_schedule::schedule_joins,
+    lan::{self, LAN_ANNOUNCE_INTERVAL},
+    last_party::{self, LastParty},
+    launch_args::{lobby_link, LaunchArgs, PORT_ARG},
+    link_stats::LinkTracker,
+    local_addr,
+    matchmaking::{self, lobby_secret},
+    nat::{NatProbe, NAT_PROBE_WAIT},
+    timers::{Timer, Timers},
+    Client, ConnectionState, Host, LocalMessage, ModCheck, ReadyCheck, User, DEFAULT_PARTY_SIZE,
 };
 
-use crate::{ConnectionState, Host, LocalMessage, User, MATCHMAKING_SERVER_ADDR};
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const RESUME_GRACE: Duration = Duration::from_secs(120);
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+const ROSTER_INTERVAL: Duration = Duration::from_secs(5);
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+const MOD_CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_MOD_PROBLEMS: usize = 16;
+const JOIN_SPACING: Duration = Duration::from_secs(2);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const HEALTH_CHECK_RETRY: Duration = Duration::from_millis(500);
+const REBIND_RETRY: Duration = Duration::from_secs(5);
+const MAX_FAILED_AUTHS: usize = 5;
+const FAILED_AUTH_WINDOW: Duration = Duration::from_secs(60);
 
 pub fn run_connections(
-    recv_tf2: Receiver<LocalMessage>,
+    recv_tf2: crossbeam_channel::Receiver<LocalMessage>,
     send_tf2: Sender<LocalMessage>,
     addr: String,
+    // its servers and port stay no matter what the settings say
+    launch_args: LaunchArgs,
+    mut servers: Vec<String>,
     order_overwrite: Option<Order>,
+    last_party: Option<LastParty>,
 ) -> Result<(), ErrorKind> {
     if order_overwrite.is_some() {
         _ = send_tf2.send(LocalMessage::ExecuteOrder(
@@ -21,269 +60,1927 @@ pub fn run_connections(
     }
 
     let mut state = ConnectionState::User(User {
-        cached_order: order_overwrite.unwrap_or_else(|| {
-            Order::JoinServer(
-                "f4bffec013fe65b634ba2ea499a86fa3".to_string(),
-                "".to_string(),
-            )
-        }),
+        cached_order: order_overwrite.unwrap_or_default(),
         ..Default::default()
     });
-    // let last_ping = Instant::now();
+    let mut timers = Timers::default();
+    let mut nat = NatProbe::default();
 
-    let stun_addr = MATCHMAKING_SERVER_ADDR
-        .parse::<SocketAddr>()
-        .expect("given stun server address is invalid");
+    // the first server is used right away, the health check moves us if it's down
+    let mut stun_addr = matchmaking::resolve_all(&servers)
+        .first()
+        .copied()
+        .unwrap_or_else(|| {
+            log::warn!(
+                "no matchmaking server to use; only LAN parties and direct connect will work"
+            );
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        });
+    let mut health = health_check(servers.clone());
+    let mut lost_lobby = false;
 
-    let mut socket = Socket::bind(addr.clone())?;
-    let (send_socket, recv_socket) = (socket.get_packet_sender(), socket.get_event_receiver());
-    std::thread::spawn(move || socket.start_polling());
+    if let Some(server) = last_party.as_ref().and_then(LastParty::server) {
+        stun_addr = server;
+    }
+
+    let socket = Socket::bind(addr.clone())?;
+    let mut bound = socket.local_addr()?;
+    let (mut send_socket, mut recv_socket, mut poll_thread) = start_polling(socket);
+    // what the gui picked; `None` is automatic
+    let mut interface = None;
+    // only a change to `p_port` moves us
+    let mut settings_port = crate::PLUGIN.wait().settings.lock().port;
+    // both wait for the user to leave the party; `Some(None)` picks the interface automatically
+    let mut pending_interface: Option<Option<Ipv4Addr>> = None;
+    let mut pending_port: Option<u16> = None;
 
     log::info!("got a socket connection {addr}");
 
-    let (send_ping, recv_ping) = std::sync::mpsc::channel();
-    let socket_send = send_socket.clone();
-    std::thread::spawn(move || run_ping_thread(socket_send, recv_ping));
+    check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+
+    let lan_parties = lan::listen().unwrap_or_else(|err| {
+        log::warn!("can't look for LAN parties: {err}");
+        crossbeam_channel::never()
+    });
+
+    if let (Some(party), ConnectionState::User(user)) = (last_party, &mut state) {
+        rejoin_last_party(user, party, &mut timers, &send_tf2);
+    }
 
     loop {
-        if let Ok(lmsg) = recv_tf2.try_recv() {
-            match (lmsg, &mut state) {
-                (LocalMessage::ConnectToLobby(lobby_id, password), ConnectionState::User(user)) => {
-                    let lobby = lobby_id.iter().collect::<String>();
-                    log::info!(
-                        "trying connecting to {} with password {}",
-                        lobby,
-                        password.iter().collect::<String>()
+        if (pending_interface.is_some() || pending_port.is_some())
+            && matches!(&state, ConnectionState::User(user) if !user.in_party())
+        {
+            let chosen = pending_interface.take();
+            let ip = match chosen {
+                Some(ip) => ip
+                    .unwrap_or_else(|| local_addr::local_ip(Some(stun_addr)))
+                    .into(),
+                None => bound.ip(),
+            };
+            let addr = SocketAddr::new(ip, pending_port.take().unwrap_or(bound.port()));
+
+            poll_thread.stop();
+
+            match Socket::bind(addr).or_else(|err| {
+                log::warn!("couldn't listen on {addr}: {err}; going back to {bound}");
+                Socket::bind(bound)
+            }) {
+                Ok(socket) => {
+                    if let Some(ip) = chosen {
+                        if socket.local_addr().is_ok_and(|local| local == addr) {
+                            interface = ip;
+                        }
+                    }
+
+                    bound = socket.local_addr().unwrap_or(bound);
+                    (send_socket, recv_socket, poll_thread) = start_polling(socket);
+                    crate::PLUGIN
+                        .wait()
+                        .local_port
+                        .store(bound.port(), Ordering::Relaxed);
+
+                    log::info!("listening on {bound}");
+
+                    check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                }
+                Err(err) => {
+                    log::error!(
+                        "couldn't listen on {bound} either: {err}; trying again in {}s",
+                        REBIND_RETRY.as_secs()
                     );
 
-                    user.password = password;
+                    recv_socket = crossbeam_channel::never();
+                    timers.schedule(REBIND_RETRY, Timer::Rebind);
+                }
+            }
+
+            // the gui shows what we actually listen on
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::BindTo(
+                interface,
+            ))));
+        }
+
+        let timer = timers
+            .next_deadline()
+            .map(crossbeam_channel::at)
+            .unwrap_or_else(crossbeam_channel::never);
+
+        crossbeam_channel::select! {
+            recv(recv_tf2) -> lmsg => {
+                let Ok(lmsg) = lmsg else {
+                    log::warn!("engine channel closed; stopping the networking thread");
+                    return Ok(());
+                };
 
+                if let LocalMessage::SettingsChanged(settings) = &lmsg {
                     _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                        LocalMessage::LobbyUid(lobby.into()),
+                        LocalMessage::SettingsChanged(settings.clone()),
                     )));
 
-                    _ = send_socket.send(Packet::reliable_unordered(
-                        stun_addr,
-                        PacketMessage::FindLobby(lobby_id)
-                            .send()
-                            .try_into()
-                            .expect("this shouldn't crash connect to lobby find lobby; report to catornot pls"),
-                    ));
-                }
-                (LocalMessage::BecomeHost(password), ConnectionState::User(_)) => {
-                    log::info!("became host");
-                    state = ConnectionState::Host(Host {
-                        password,
-                        ..Default::default()
-                    });
-
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
-                        true,
-                    ))));
-
-                    _ = send_socket.send(Packet::reliable_unordered(
-                        stun_addr,
-                        PacketMessage::CreateLobby
-                            .send()
-                            .try_into()
-                            .expect("this shouldn't crash connect to lobby find lobby; report to catornot pls"),
-                    ));
+                    let list = matchmaking::server_list(
+                        &launch_args.servers,
+                        &settings.matchmaking_servers,
+                    );
+                    if list != servers {
+                        log::info!("matchmaking servers are now {}", list.join(", "));
+
+                        servers = list;
+                        health = health_check(servers.clone());
+                    }
                 }
-                (LocalMessage::BecomeUser, ConnectionState::Host(_)) => {
-                    log::info!("became user");
-                    state = ConnectionState::User(User::default());
-
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
-                        false,
-                    ))));
-
-                    if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
-                        #[allow(unused_unsafe)] // wtf
-                        unsafe {
-                            invite_hanlder.copy().clear_secret()
+
+                match &lmsg {
+                    LocalMessage::BindTo(ip) => pending_interface = Some(*ip),
+                    LocalMessage::SettingsChanged(settings) if settings.port != settings_port => {
+                        settings_port = settings.port;
+
+                        match launch_args.port {
+                            Some(port) => log::info!(
+                                "staying on port {port} from the {PORT_ARG} launch arg; p_port is used next time"
+                            ),
+                            None => pending_port = Some(settings.port),
                         }
                     }
+                    _ => {}
                 }
-                (LocalMessage::Leave, _) => {
-                    log::info!("left current state");
-                    state = ConnectionState::User(User::default());
 
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
-                        false,
-                    ))));
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                        LocalMessage::LobbyUid(None),
-                    )));
+                if let LocalMessage::BindTo(_) | LocalMessage::SettingsChanged(_) = lmsg {
+                    if (pending_interface.is_some() || pending_port.is_some())
+                        && !matches!(&state, ConnectionState::User(user) if !user.in_party())
+                    {
+                        log::info!("the new address is used once you leave the party");
+                    }
+                    continue;
                 }
-                (LocalMessage::NewOrder(order), ConnectionState::Host(host)) => {
-                    log::info!("sending order : {order:?}");
 
-                    host.last_order = order.clone();
-
-                    host.clients
-                        .iter()
-                        .map(|(addr, id)| {
-                            (
-                                PacketMessage::NewOrder(*id, order.clone())
-                                    .send()
-                                    .try_into(),
-                                addr,
-                            )
-                        })
-                        .filter_map(|(maybe_err, addr)| {
-                            Some((
-                                maybe_err
-                                    .map_err(|err| log::warn!("failed to build order packet {err}"))
-                                    .ok()?,
-                                addr,
-                            ))
-                        })
-                        .for_each(|(packet, addr)| {
-                            _ = send_socket.send(Packet::reliable_unordered(*addr, packet))
-                        });
+                if let LocalMessage::CheckNat = lmsg {
+                    check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                    continue;
                 }
-                (LocalMessage::GetCachedOrder, ConnectionState::User(user)) => {
-                    log::info!("getting cached order");
-                    _ = send_tf2.send(LocalMessage::ExecuteOrder(user.cached_order.clone()));
+
+                // links say where the lobby lives
+                if let LocalMessage::ConnectToLobby(_, _, Some(server)) = lmsg {
+                    if server != stun_addr && !holds_lobby(&state) {
+                        log::info!("the lobby lives on {server}; using it as the matchmaking server");
+
+                        stun_addr = server;
+                        check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                    }
                 }
-                (LocalMessage::ForwardToEngine(msg), _) => {
-                    _ = send_tf2.send(*msg);
+
+                process_local_message(
+                    lmsg,
+                    &mut state,
+                    &send_socket,
+                    &mut timers,
+                    stun_addr,
+                    &send_tf2,
+                );
+            }
+            recv(recv_socket) -> event => {
+                let Ok(event) = event else {
+                    log::warn!("socket channel closed; stopping the networking thread");
+                    return Ok(());
+                };
+
+                if matches!(&event, SocketEvent::Disconnect(addr) if *addr == stun_addr)
+                    && matches!(&state, ConnectionState::Host(host) if !host.lan)
+                {
+                    log::warn!("lost the matchmaking server; looking for one that answers");
+
+                    lost_lobby = true;
+                    health = health_check(servers.clone());
                 }
-                (
-                    LocalMessage::BecomeUser
-                    | LocalMessage::GetCachedOrder
-                    | LocalMessage::BecomeHost(_)
-                    | LocalMessage::ConnectToLobby(_, _)
-                    | LocalMessage::ExecuteOrder(_)
-                    | LocalMessage::NewOrder(_)
-                    | LocalMessage::ExecuteConCommand(_)
-                    | LocalMessage::ForwardToGui(_)
-                    | LocalMessage::ExecuteFunction(_)
-                    | LocalMessage::LobbyUid(_)
-                    | LocalMessage::NewConnection(_)
-                    | LocalMessage::DroppedConnection(_)
-                    | LocalMessage::IsHost(_),
-                    _,
-                ) => {}
-            }
-        }
-
-        // const SECONDS_2: Duration = Duration::from_secs(2);
-        // if last_ping.elapsed() > SECONDS_2 {
-        //     match &state {
-        //         ConnectionState::User(user) if user.server.is_some() => {
-        //             _ = send_socket.send(Packet::unreliable(
-        //                 user.server.unwrap(),
-        //                 PacketMessage::Ping(Some(user.uid))
-        //                     .send()
-        //                     .try_into()
-        //                     .expect("ping build failed"),
-        //             ));
-        //         }
-        //         ConnectionState::Host(host)
-        //             if host.lobby_id.is_some() && host.lobby_id.unwrap() != LobbyUid::default() =>
-        //         {
-        //             _ = send_socket.send(Packet::unreliable(
-        //                 MATCHMAKING_SERVER_ADDR,
-        //                 PacketMessage::Ping(None)
-        //                     .send()
-        //                     .try_into()
-        //                     .expect("ping build failed"),
-        //             ));
-        //         }
-        //         _ => {}
-        //     }
-        // }
-
-        let Ok(event) = recv_socket.try_recv() else {
-            continue;
-        };
 
-        match (event, &mut state) {
-            (SocketEvent::Packet(packet), _) => {
-                let recv_packet: SentPacket = match packet.payload().try_into() {
-                    Ok(p) => p,
-                    Err(err) => {
-                        log::info!("packet desiriazation failed {err}");
-                        continue;
+                process_socket_event(
+                    event,
+                    &mut state,
+                    &mut nat,
+                    &send_socket,
+                    &mut timers,
+                    stun_addr,
+                    &send_tf2,
+                );
+
+                // members follow the lobby to the host's new matchmaking server
+                let moved_to = match &state {
+                    ConnectionState::User(user) if user.in_party() => {
+                        user.roster.as_ref().and_then(|roster| roster.matchmaking)
                     }
+                    _ => None,
                 };
-                let addr = packet.addr();
 
-                let maybe_err = match recv_packet {
-                    SentPacket::PacketMessage(msg) => match &mut state {
-                        ConnectionState::Host(host) => {
-                            process_message_host(addr, msg, &send_socket, host, &send_tf2)
-                        }
-                        ConnectionState::User(user) => {
-                            process_message_user(addr, msg, &send_socket, user, &send_tf2)
-                        }
-                    },
-                    SentPacket::PacketResponse(response) => process_response(
-                        addr,
-                        response,
-                        &send_socket,
-                        &mut state,
-                        &send_ping,
-                        stun_addr,
-                        &send_tf2,
-                    ),
+                if let Some(server) = moved_to.filter(|server| *server != stun_addr) {
+                    log::info!("the lobby moved to {server}; using it as the matchmaking server");
+
+                    stun_addr = server;
+                    check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                }
+            }
+            recv(lan_parties) -> announcement => {
+                if let Ok((announcement, addr)) = announcement {
+                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                        LocalMessage::LanAnnounced(announcement, addr),
+                    )));
+                }
+            }
+            recv(health) -> healthy => {
+                health = crossbeam_channel::never();
+
+                let Ok(Some(healthy)) = healthy else {
+                    log::warn!("none of the matchmaking servers answered");
+                    continue;
                 };
 
-                if let Err(err) = maybe_err {
-                    if let (PartyaError::IllegalUid(_, addr), ConnectionState::Host(host)) =
-                        (&err, &mut state)
-                    {
-                        remove_from_host(host, addr);
-                    }
+                if healthy != stun_addr && (lost_lobby || !holds_lobby(&state)) {
+                    log::info!("using the matchmaking server at {healthy}");
 
-                    log::error!("{err}");
+                    stun_addr = healthy;
+                    check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                }
+
+                // the old lobby id is gone with the server so the party gets a new one
+                if std::mem::take(&mut lost_lobby) {
+                    if let ConnectionState::Host(host) = &mut state {
+                        if host.lobby_id.is_some() {
+                            continue;
+                        }
+
+                        log::info!("registering the party again");
+
+                        host.stun_link = LinkTracker::default();
+                        _ = send_socket.send(Packet::reliable_unordered(
+                            stun_addr,
+                            PacketMessage::CreateLobby.send().try_into().expect(
+                                "this shouldn't crash registering the lobby again; report to catornot pls",
+                            ),
+                        ));
+                    }
                 }
             }
-            (SocketEvent::Connect(_), ConnectionState::User(_)) => {}
-            (SocketEvent::Connect(_), _) => {}
-            (SocketEvent::Timeout(_), _) => {}
-            (SocketEvent::Disconnect(addr), ConnectionState::User(user)) => {
-                // log::warn!("{} disconnected", addr);
+            recv(timer) -> _ => {}
+        }
 
-                if user.server == Some(addr) {
-                    log::warn!("disconnected from lobby");
-                    user.server = None;
+        for timer in timers.take_due(Instant::now()) {
+            if timer == Timer::Rebind {
+                match Socket::bind(bound) {
+                    Ok(socket) => {
+                        (send_socket, recv_socket, poll_thread) = start_polling(socket);
 
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                        LocalMessage::LobbyUid(None),
-                    )));
+                        log::info!("listening on {bound} again");
 
-                    if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
-                        unsafe { invite_hanlder.copy().clear_secret() };
+                        check_nat(&mut nat, bound, &send_socket, &mut timers, stun_addr);
+                    }
+                    Err(err) => {
+                        log::warn!("still can't listen on {bound}: {err}");
+                        timers.schedule(REBIND_RETRY, Timer::Rebind);
                     }
                 }
+                continue;
+            }
+
+            process_timer(
+                timer,
+                &mut state,
+                &mut nat,
+                &send_socket,
+                &mut timers,
+                stun_addr,
+                &send_tf2,
+            );
+        }
+    }
+}
+
+/// asks both of the matchmaking server's ports how they see us, the verdict comes with `Timer::NatVerdict`
+fn check_nat(
+    nat: &mut NatProbe,
+    bound: SocketAddr,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    stun_addr: SocketAddr,
+) {
+    let local = if bound.ip().is_unspecified() {
+        SocketAddr::from((local_addr::local_ip(Some(stun_addr)), bound.port()))
+    } else {
+        bound
+    };
+
+    *nat = NatProbe::new(nat.id.wrapping_add(1), local);
+    timers.cancel(|timer| matches!(timer, Timer::NatProbe(_) | Timer::NatVerdict(_)));
+
+    _ = send_socket.send(Packet::unreliable(
+        stun_addr,
+        PacketMessage::NatProbe(nat.id, true)
+            .send()
+            .try_into()
+            .expect("nat probes should always serialize"),
+    ));
+    timers.schedule(NAT_PROBE_WAIT, Timer::NatProbe(nat.id));
+}
+
+/// the lobby is registered with the current matchmaking server so we can't move away from it
+fn holds_lobby(state: &ConnectionState) -> bool {
+    match state {
+        ConnectionState::Host(host) => !host.lan,
+        ConnectionState::User(user) => user.lobby_id.is_some() && user.direct.is_none(),
+    }
+}
+
+/// pings each server in order on a throwaway socket and hands back the first one that answers
+fn health_check(servers: Vec<String>) -> crossbeam_channel::Receiver<Option<SocketAddr>> {
+    let (send, recv) = crossbeam_channel::bounded(1);
+
+    std::thread::spawn(move || {
+        let healthy = matchmaking::resolve_all(&servers)
+            .into_iter()
+            .find(|server| is_healthy(*server));
+
+        _ = send.send(healthy);
+    });
+
+    recv
+}
+
+fn is_healthy(server: SocketAddr) -> bool {
+    // `bind_any` only listens on localhost
+    let Ok(mut socket) = Socket::bind((Ipv4Addr::UNSPECIFIED, 0)) else {
+        return false;
+    };
+    let (send_socket, recv_socket) = (socket.get_packet_sender(), socket.get_event_receiver());
+    let ping: Result<Vec<u8>, _> = PacketMessage::Ping(None, 0).send().try_into();
+    let Ok(ping) = ping else {
+        return false;
+    };
+
+    let deadline = Instant::now() + HEALTH_CHECK_TIMEOUT;
+    let mut next_ping = Instant::now();
+
+    while Instant::now() < deadline {
+        // pings are unreliable so a lost one gets sent again
+        if Instant::now() >= next_ping {
+            _ = send_socket.send(Packet::unreliable(server, ping.clone()));
+            next_ping += HEALTH_CHECK_RETRY;
+        }
+
+        socket.manual_poll(Instant::now());
+
+        while let Ok(event) = recv_socket.try_recv() {
+            let SocketEvent::Packet(packet) = event else {
+                continue;
+            };
+            let response: Result<SentPacket, _> = packet.payload().try_into();
+
+            if packet.addr() == server
+                && matches!(
+                    response,
+                    Ok(SentPacket::PacketResponse(PacketResponse::Pong(_)))
+                )
+            {
+                return true;
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    log::warn!("the matchmaking server at {server} didn't answer");
+    false
+}
+
+/// like `Socket::start_polling` but it can be stopped to free the address again
+///
+/// laminar only reads its socket when polled and can't block on it, so waking every millisecond
+/// is the floor; the select loop itself only wakes up for packets, messages and timers
+fn start_polling(
+    mut socket: Socket,
+) -> (
+    crossbeam_channel::Sender<Packet>,
+    crossbeam_channel::Receiver<SocketEvent>,
+    PollThread,
+) {
+    let (send_socket, recv_socket) = (socket.get_packet_sender(), socket.get_event_receiver());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = std::thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                socket.manual_poll(Instant::now());
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    });
+
+    (
+        send_socket,
+        recv_socket,
+        PollThread {
+            stop,
+            thread: Some(thread),
+        },
+    )
+}
+
+/// owns the socket; stopping it frees the address
+struct PollThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PollThread {
+    /// does nothing if it already stopped
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+fn process_local_message(
+    lmsg: LocalMessage,
+    state: &mut ConnectionState,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    stun_addr: SocketAddr,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    match (lmsg, &mut *state) {
+        (LocalMessage::ConnectToLobby(lobby_id, password, _), ConnectionState::User(user)) => {
+            let lobby = lobby_id.iter().collect::<String>();
+            log::info!("trying connecting to {}", lobby);
+
+            user.password = password;
+            user.lobby_id = Some(lobby_id);
+            user.resume_token = None;
+            user.restored = false;
+            user.direct = None;
+            user.last_party = None;
+
+            if let Some(party) =
+                last_party::load().filter(|party| party.matches(lobby_id, &password))
+            {
+                if let (Some(uid), Some(resume_token)) = (party.uid(), party.resume_token()) {
+                    log::info!("we were in this party before; resuming our place");
+                    user.uid = uid;
+                    user.resume_token = Some(resume_token);
+                    user.last_party = Some(party);
+                }
+            }
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LobbyUid(lobby.into()),
+            )));
+
+            _ = send_socket.send(Packet::reliable_unordered(
+                stun_addr,
+                PacketMessage::FindLobby(lobby_id).send().try_into().expect(
+                    "this shouldn't crash connect to lobby find lobby; report to catornot pls",
+                ),
+            ));
+
+            timers.cancel(|timer| *timer == Timer::ConnectTimeout);
+            timers.schedule(CONNECT_TIMEOUT, Timer::ConnectTimeout);
+        }
+        (LocalMessage::ConnectDirect(addr, lobby_id, password), ConnectionState::User(user)) => {
+            log::info!("connecting directly to the party at {addr}");
+
+            user.password = password;
+            user.lobby_id = lobby_id;
+            user.resume_token = None;
+            user.restored = false;
+            user.direct = Some(addr);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LobbyUid(Some(
+                    lobby_id
+                        .map(|lobby_id| lobby_id.iter().collect())
+                        .unwrap_or_else(|| addr.to_string()),
+                )),
+            )));
+
+            connect_directly(user, addr, send_socket);
+
+            timers.cancel(|timer| *timer == Timer::ConnectTimeout);
+            timers.schedule(CONNECT_TIMEOUT, Timer::ConnectTimeout);
+        }
+        (LocalMessage::BecomeHost(password), ConnectionState::User(_)) => {
+            log::info!("became host");
+            last_party::forget();
+            let lan = crate::PLUGIN.wait().lan_mode.load(Ordering::Relaxed);
+
+            *state = ConnectionState::Host(Host {
+                uid: new_player_uid(),
+                password,
+                max_size: DEFAULT_PARTY_SIZE,
+                lan,
+                lobby_id: lan.then(new_lobby_id),
+                ..Default::default()
+            });
+            timers.clear();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
+                true,
+            ))));
+
+            if let ConnectionState::Host(Host {
+                lobby_id: Some(lobby_id),
+                lan: true,
+                ..
+            }) = state
+            {
+                log::info!("hosting a LAN party");
+
+                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                    LocalMessage::LobbyUid(Some(lobby_id.iter().collect())),
+                )));
+
+                timers.schedule(Duration::ZERO, Timer::LanAnnounce);
+                return;
+            }
+
+            _ = send_socket.send(Packet::reliable_unordered(
+                stun_addr,
+                PacketMessage::CreateLobby.send().try_into().expect(
+                    "this shouldn't crash connect to lobby find lobby; report to catornot pls",
+                ),
+            ));
+        }
+        (LocalMessage::BecomeUser, ConnectionState::Host(_)) => {
+            log::info!("became user");
+            *state = ConnectionState::User(User::default());
+            timers.clear();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
+                false,
+            ))));
+
+            if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
+                #[allow(unused_unsafe)] // wtf
+                unsafe {
+                    invite_hanlder.copy().clear_secret()
+                }
+            }
+        }
+        (LocalMessage::Leave, _) => {
+            log::info!("left current state");
+            last_party::forget();
+            *state = ConnectionState::User(User::default());
+            timers.clear();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
+                false,
+            ))));
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LobbyUid(None),
+            )));
+        }
+        (LocalMessage::NewOrder(order), ConnectionState::Host(host)) => {
+            give_order(host, order, send_socket, timers, send_tf2)
+        }
+        (LocalMessage::StageOrder(order), ConnectionState::Host(host)) => {
+            let required_mods = match &order {
+                Order::JoinServer { required_mods, .. }
+                    if !required_mods.is_empty() && !host.clients.is_empty() =>
+                {
+                    required_mods.clone()
+                }
+                _ => return give_order(host, order, send_socket, timers, send_tf2),
+            };
+
+            log::info!("checking the party's mods before sending the order");
+
+            host.staged_order = Some(order);
+            start_mod_check(host, required_mods, send_socket, timers, send_tf2);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::OrderStaged(true),
+            )));
+        }
+        (LocalMessage::RevokeInvite, ConnectionState::Host(host)) => {
+            log::info!("revoked the party link");
+
+            // members that already joined with the old link stay
+            host.invite = Some(new_lobby_id());
+            share_party_link(host, send_tf2);
+        }
+        (LocalMessage::ConfirmOrder, ConnectionState::Host(host)) => {
+            match host.staged_order.take() {
+                Some(order) => give_order(host, order, send_socket, timers, send_tf2),
+                None => log::info!("there is no order waiting to be sent"),
+            }
+        }
+        (LocalMessage::GetCachedOrder, ConnectionState::User(user)) => {
+            log::info!("getting cached order");
+            _ = send_tf2.send(LocalMessage::ExecuteOrder(user.cached_order.clone()));
+        }
+        (LocalMessage::GetLinkStats, ConnectionState::Host(host)) => {
+            let stats = host
+                .clients
+                .iter()
+                .map(|client| (client.uid.iter().collect(), client.link.stats()))
+                .chain(std::iter::once((
+                    "matchmaking server".to_string(),
+                    host.stun_link.stats(),
+                )))
+                .collect();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LinkStats(stats),
+            )));
+        }
+        (LocalMessage::GetLinkStats, ConnectionState::User(user)) => {
+            let stats = user
+                .server
+                .map(|_| ("host".to_string(), user.server_link.stats()))
+                .into_iter()
+                .collect();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LinkStats(stats),
+            )));
+        }
+        (LocalMessage::SendChat(text), ConnectionState::Host(host)) => {
+            let sender = crate::PLUGIN.wait().profile.lock().display_name();
+            relay_chat(host, sender, text, send_socket, send_tf2);
+        }
+        (LocalMessage::SendChat(text), ConnectionState::User(user)) => {
+            let Some(server) = user.server else {
+                log::warn!("you aren't in a party");
+                return;
+            };
+
+            match PacketMessage::PartyChat(user.uid, text).send().try_into() {
+                Ok(packet) => _ = send_socket.send(Packet::reliable_ordered(server, packet, None)),
+                Err(err) => log::warn!("failed to build chat packet {err}"),
+            }
+        }
+        (LocalMessage::ListMembers, ConnectionState::Host(host)) => {
+            log::info!("party members ({}):", host.clients.len() + 1);
+            log::info!(
+                "  {} {} (host, you)",
+                host.uid.iter().collect::<String>(),
+                crate::PLUGIN.wait().profile.lock().display_name()
+            );
+            for client in host.clients.iter() {
+                log::info!(
+                    "  {} {}",
+                    client.uid.iter().collect::<String>(),
+                    client.info.display_name()
+                );
+            }
+            for (client, _) in host.suspended.iter() {
+                log::info!(
+                    "  {} {} (reconnecting)",
+                    client.uid.iter().collect::<String>(),
+                    client.info.display_name()
+                );
+            }
+        }
+        (LocalMessage::ListMembers, ConnectionState::User(user)) => {
+            if !user.in_party() {
+                log::info!("you are not in a party");
+                return;
+            }
+
+            log::info!("party members ({}):", user.members.len() + 1);
+            log::info!(
+                "  {} {} (you)",
+                user.uid.iter().collect::<String>(),
+                crate::PLUGIN.wait().profile.lock().display_name()
+            );
+            for (uid, info) in user.members.iter() {
+                log::info!(
+                    "  {} {}",
+                    uid.iter().collect::<String>(),
+                    info.display_name()
+                );
+            }
+        }
+        (LocalMessage::StartReadyCheck, ConnectionState::Host(host)) => {
+            if host.clients.is_empty() {
+                log::info!("there is nobody in the party to ready check");
+                return;
+            }
+
+            let id = host
+                .ready_check
+                .as_ref()
+                .map(|check| check.id.wrapping_add(1))
+                .unwrap_or_default();
+
+            log::info!("starting a ready check");
+
+            host.ready_check = Some(ReadyCheck {
+                id,
+                answers: host
+                    .clients
+                    .iter()
+                    .map(|client| (client.uid, None))
+                    .collect(),
+                open: true,
+            });
+
+            let timeout = READY_CHECK_TIMEOUT.as_secs() as u32;
+            broadcast(host, send_socket, |uid| {
+                PacketMessage::ReadyCheck(uid, id, timeout)
+            });
+
+            timers.cancel(|timer| matches!(timer, Timer::ReadyCheckTimeout(_)));
+            timers.schedule(READY_CHECK_TIMEOUT, Timer::ReadyCheckTimeout(id));
+
+            send_ready_tally(host, send_tf2);
+        }
+        (LocalMessage::StartReadyCheck, ConnectionState::User(_)) => {
+            log::info!("only the host can start a ready check");
+        }
+        (LocalMessage::AnswerReady(ready), ConnectionState::User(user)) => {
+            let (Some(server), Some(id)) = (user.server, user.ready_prompt.take()) else {
+                log::info!("there is no ready check to answer");
+                return;
+            };
+
+            timers.cancel(|timer| *timer == Timer::ReadyCheckTimeout(id));
+
+            match PacketMessage::ReadyAnswer(user.uid, id, ready)
+                .send()
+                .try_into()
+            {
+                Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(server, packet)),
+                Err(err) => log::warn!("failed to build ready packet {err}"),
+            }
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::ReadyPrompt(None),
+            )));
+        }
+        (LocalMessage::AnswerReady(_), ConnectionState::Host(_)) => {
+            log::info!("the host doesn't answer ready checks");
+        }
+        (LocalMessage::Kick(member, reason, ban), ConnectionState::Host(host)) => {
+            kick_member(host, &member, reason, ban, send_socket, timers, send_tf2);
+        }
+        (LocalMessage::Kick(_, _, _), ConnectionState::User(_)) => {
+            log::info!("only the host can kick members");
+        }
+        (LocalMessage::SetPartySize(max_size), ConnectionState::Host(host)) => {
+            host.max_size = max_size.max(2);
+            log::info!("party size limit set to {}", host.max_size);
+        }
+        (LocalMessage::LockLobby(locked), ConnectionState::Host(host)) => {
+            host.locked = locked;
+            log::info!("lobby {}", if locked { "locked" } else { "unlocked" });
+        }
+        (LocalMessage::SetPartySize(_) | LocalMessage::LockLobby(_), ConnectionState::User(_)) => {
+            log::info!("only the host can change the lobby settings");
+        }
+        (LocalMessage::ReportOrderStatus(status), ConnectionState::User(user)) => {
+            if !user.order_pending {
+                return;
+            }
+            user.order_pending = !status.is_final();
+
+            report_order_status(user, status, send_socket);
+        }
+        (LocalMessage::ReportOrderStatus(_), ConnectionState::Host(_)) => {}
+        (LocalMessage::JoinedServer(id, name), ConnectionState::User(user)) => {
+            if !user.order_pending {
+                return;
             }
-            (SocketEvent::Disconnect(addr), ConnectionState::Host(host)) => {
-                if let Some(conn) = host.clients.iter().find(|(a, _)| addr == *a) {
-                    log::info!("{} disconnect", conn.1.into_iter().collect::<String>());
+
+            // hosts on older versions order by the server's name
+            match &user.cached_order {
+                Order::JoinServer { id: ordered, .. } if *ordered == id || *ordered == name => {
+                    user.order_pending = false;
+                    report_order_status(user, OrderStatus::Connected, send_socket);
                 }
+                _ => log::info!("joined a server the party wasn't ordered to"),
+            }
+        }
+        (LocalMessage::JoinedServer(_, _), ConnectionState::Host(_)) => {}
+        (LocalMessage::CheckMods, ConnectionState::Host(host)) => {
+            let Some(Order::JoinServer { required_mods, .. }) =
+                host.staged_order.as_ref().or(Some(&host.last_order))
+            else {
+                log::info!("join a server first so there is something to check");
+                return;
+            };
+            let required_mods = required_mods.clone();
+
+            start_mod_check(host, required_mods, send_socket, timers, send_tf2);
+        }
+        (LocalMessage::PrioritizeJoin(members), ConnectionState::Host(host)) => {
+            let first = members
+                .iter()
+                .filter_map(|member| {
+                    let uid = host
+                        .clients
+                        .iter()
+                        .chain(host.suspended.iter().map(|(client, _)| client))
+                        .find(|client| member_matches(client, member))
+                        .map(|client| client.uid);
+
+                    if uid.is_none() {
+                        log::warn!("there is no member called {member} in the party");
+                    }
+                    uid
+                })
+                .collect::<Vec<_>>();
+
+            host.join_priority.retain(|uid| !first.contains(uid));
+            host.join_priority.splice(0..0, first);
+        }
+        (LocalMessage::FindServer(mut criteria), ConnectionState::Host(host)) => {
+            criteria.party_size = (host.clients.len() + host.suspended.len() + 1) as u32;
+
+            log::info!("looking for a server for {} players", criteria.party_size);
+            _ = send_tf2.send(LocalMessage::SearchServers(criteria));
+        }
+        (LocalMessage::FindServer(_), ConnectionState::User(_)) => {
+            log::info!("only the host can pick a server for the party");
+        }
+        (LocalMessage::PrioritizeJoin(_), ConnectionState::User(_)) => {
+            log::info!("only the host can change the join order");
+        }
+        (LocalMessage::CheckMods, ConnectionState::User(_)) => {
+            log::info!("only the host can check mods");
+        }
+        (LocalMessage::PreflightResult(problems), ConnectionState::User(user)) => {
+            let (Some(server), Some(id)) = (user.server, user.preflight.take()) else {
+                return;
+            };
+
+            match PacketMessage::PreflightResult(user.uid, id, problems)
+                .send()
+                .try_into()
+            {
+                Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(server, packet)),
+                Err(err) => log::warn!("failed to build preflight packet {err}"),
+            }
+        }
+        (LocalMessage::PreflightResult(_), ConnectionState::Host(_)) => {}
+        (LocalMessage::ForwardToEngine(msg), _) => {
+            _ = send_tf2.send(*msg);
+        }
+        (
+            LocalMessage::BecomeUser
+            | LocalMessage::GetCachedOrder
+            | LocalMessage::BecomeHost(_)
+            | LocalMessage::ConnectToLobby(_, _, _)
+            | LocalMessage::ExecuteOrder(_)
+            | LocalMessage::SearchServers(_)
+            | LocalMessage::NewOrder(_)
+            | LocalMessage::StageOrder(_)
+            | LocalMessage::ConfirmOrder
+            | LocalMessage::OrderStaged(_)
+            | LocalMessage::ExecuteConCommand(_)
+            | LocalMessage::ForwardToGui(_)
+            | LocalMessage::ExecuteFunction(_)
+            | LocalMessage::LobbyUid(_)
+            | LocalMessage::NewConnection(_, _)
+            | LocalMessage::DroppedConnection(_)
+            | LocalMessage::Reconnecting(_)
+            | LocalMessage::LinkStats(_)
+            | LocalMessage::ChatMessage(_, _)
+            | LocalMessage::ReadyPrompt(_)
+            | LocalMessage::ReadyTally(_, _)
+            | LocalMessage::Notice(_)
+            | LocalMessage::OrderStatuses(_)
+            | LocalMessage::PreflightMods(_)
+            | LocalMessage::ModCheckReport(_, _)
+            | LocalMessage::HasOrder(_)
+            | LocalMessage::BindTo(_)
+            | LocalMessage::PartyLink(_)
+            | LocalMessage::RevokeInvite
+            | LocalMessage::LanAnnounced(_, _)
+            | LocalMessage::ConnectDirect(_, _, _)
+            | LocalMessage::CheckNat
+            | LocalMessage::NatType(_)
+            | LocalMessage::SettingsChanged(_)
+            | LocalMessage::IsHost(_),
+            _,
+        ) => {}
+    }
+}
+
+fn process_socket_event(
+    event: SocketEvent,
+    state: &mut ConnectionState,
+    nat: &mut NatProbe,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    stun_addr: SocketAddr,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    match (event, &mut *state) {
+        (SocketEvent::Packet(packet), _) => {
+            let recv_packet: SentPacket = match packet.payload().try_into() {
+                Ok(p) => p,
+                Err(err) => {
+                    log::info!("packet desiriazation failed {err}");
+                    return;
+                }
+            };
+            let addr = packet.addr();
+
+            let maybe_err = match recv_packet {
+                SentPacket::PacketMessage(msg) => match state {
+                    ConnectionState::Host(host) => {
+                        process_message_host(addr, msg, send_socket, host, timers, send_tf2)
+                    }
+                    ConnectionState::User(user) => {
+                        process_message_user(addr, msg, send_socket, user, timers, send_tf2)
+                    }
+                },
+                SentPacket::PacketResponse(response) => process_response(
+                    addr,
+                    response,
+                    send_socket,
+                    state,
+                    nat,
+                    timers,
+                    stun_addr,
+                    send_tf2,
+                ),
+            };
+
+            if let Err(err) = maybe_err {
+                if let (PartyaError::IllegalUid(_, addr), ConnectionState::Host(host)) =
+                    (&err, state)
+                {
+                    remove_from_host(host, addr);
+                }
+
+                log::error!("{err}");
+            }
+        }
+        (SocketEvent::Connect(_), ConnectionState::User(_)) => {}
+        (SocketEvent::Connect(_), _) => {}
+        (SocketEvent::Timeout(_), _) => {}
+        (SocketEvent::Disconnect(addr), ConnectionState::User(user)) => {
+            // log::warn!("{} disconnected", addr);
+
+            if user.server == Some(addr) {
+                log::warn!("disconnected from lobby");
+                user.server = None;
+                timers.cancel(|timer| matches!(timer, Timer::Ping(a, _) if *a == addr));
+
+                if user.in_party() && user.resume_token.is_some() {
+                    log::info!("trying to reconnect to the lobby");
+
+                    user.reconnect_attempts = 0;
+                    timers.schedule(reconnect_backoff(0), Timer::Reconnect);
+
+                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                        LocalMessage::Reconnecting(true),
+                    )));
+                } else {
+                    give_up_lobby(user, send_tf2);
+                }
+            }
+        }
+        (SocketEvent::Disconnect(addr), ConnectionState::Host(host)) => {
+            timers.cancel(|timer| matches!(timer, Timer::Ping(a, _) if *a == addr));
+
+            if let Some(i) = host.clients.iter().position(|client| client.addr == addr) {
+                let client = host.clients.swap_remove(i);
+                log::info!(
+                    "{} disconnect; keeping their spot for a bit",
+                    client.uid.iter().collect::<String>()
+                );
+
+                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                    LocalMessage::DroppedConnection(client.uid.iter().collect()),
+                )));
+                broadcast(host, send_socket, |uid| {
+                    PacketMessage::MemberLeft(uid, client.uid)
+                });
+
+                timers.schedule(RESUME_GRACE, Timer::ForgetClient(client.uid));
+                host.suspended.push((client, Instant::now() + RESUME_GRACE));
+            }
+
+            if addr == stun_addr {
+                log::warn!("disconnected from stun server");
+                host.lobby_id = None;
+
+                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                    LocalMessage::LobbyUid(None),
+                )));
+
+                if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
+                    unsafe { invite_hanlder.copy().clear_secret() };
+                }
+            }
+        }
+    }
+}
+
+fn process_timer(
+    timer: Timer,
+    state: &mut ConnectionState,
+    nat: &mut NatProbe,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    stun_addr: SocketAddr,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    match (timer, state) {
+        (Timer::Ping(addr, uid), state) => {
+            let link = match state {
+                ConnectionState::Host(host) if addr == stun_addr => Some(&mut host.stun_link),
+                ConnectionState::Host(host) => host
+                    .clients
+                    .iter_mut()
+                    .find(|client| client.addr == addr)
+                    .map(|client| &mut client.link),
+                ConnectionState::User(user) if user.server == Some(addr) => {
+                    Some(&mut user.server_link)
+                }
+                ConnectionState::User(_) => None,
+            };
+
+            // the peer is gone so stop pinging it
+            let Some(link) = link else {
+                return;
+            };
+
+            // unreliable so retransmits don't end up in the measurements
+            _ = send_socket.send(Packet::unreliable(
+                addr,
+                PacketMessage::Ping(uid, link.next_ping(Instant::now()))
+                    .send()
+                    .try_into()
+                    .unwrap(),
+            ));
+
+            timers.schedule(ping_interval(), Timer::Ping(addr, uid));
+        }
+        (Timer::ConnectTimeout, ConnectionState::User(user)) if user.server.is_none() => {
+            log::warn!("couldn't reach the lobby in time; try again or ask the host for a new id");
+
+            user.connect_to = None;
+            timers.cancel(|timer| *timer == Timer::ConnectTimeout);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LobbyUid(None),
+            )));
+        }
+        (Timer::ConnectTimeout, _) => {}
+        (Timer::Reconnect, ConnectionState::User(user)) if user.server.is_none() => {
+            if !user.in_party() {
+                return;
+            }
+
+            if user.reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+                log::warn!("couldn't reconnect to the lobby; giving up");
+                give_up_lobby(user, send_tf2);
+                return;
+            }
+
+            user.reconnect_attempts += 1;
+            log::info!("reconnect attempt {}", user.reconnect_attempts);
+
+            if let Some(addr) = user.direct {
+                connect_directly(user, addr, send_socket);
+            } else if let Some(lobby_id) = user.lobby_id {
+                _ = send_socket.send(Packet::reliable_unordered(
+                    stun_addr,
+                    PacketMessage::FindLobby(lobby_id).send().try_into().expect(
+                        "this shouldn't crash reconnect find lobby; report to catornot pls",
+                    ),
+                ));
+            }
+
+            timers.schedule(reconnect_backoff(user.reconnect_attempts), Timer::Reconnect);
+        }
+        (Timer::Reconnect, _) => {}
+        (Timer::LanAnnounce, ConnectionState::Host(host)) if host.lan => {
+            let Some(lobby_id) = host.lobby_id else {
+                return;
+            };
+
+            let announcement = LanAnnouncement {
+                lobby_id,
+                host: crate::PLUGIN.wait().profile.lock().display_name(),
+                members: (host.clients.len() + 1).min(u8::MAX as usize) as u8,
+                max_size: host.max_size,
+                locked: host.locked,
+                port: crate::PLUGIN.wait().local_port.load(Ordering::Relaxed),
+            };
+
+            if let Err(err) = lan::announce(&announcement) {
+                log::warn!("couldn't announce the LAN party: {err}");
+            }
+
+            timers.schedule(LAN_ANNOUNCE_INTERVAL, Timer::LanAnnounce);
+        }
+        (Timer::LanAnnounce, _) => {}
+        (Timer::NatProbe(id), _) if id == nat.id => {
+            // a restricted nat only lets the probe port through once we've talked to it
+            if let Some(probe_port) = nat.probe_port {
+                nat.asked_secondary = true;
+
+                _ = send_socket.send(Packet::unreliable(
+                    SocketAddr::new(stun_addr.ip(), probe_port),
+                    PacketMessage::NatProbe(id, false)
+                        .send()
+                        .try_into()
+                        .expect("nat probes should always serialize"),
+                ));
+            }
+
+            timers.schedule(NAT_PROBE_WAIT, Timer::NatVerdict(id));
+        }
+        (Timer::NatVerdict(id), _) if id == nat.id => {
+            let nat_type = nat.classify();
+
+            if nat_type.hole_punching_unlikely() {
+                log::warn!(
+                    "nat type is {nat_type}; members will likely fail to reach a party hosted here"
+                );
+            } else {
+                log::info!("nat type is {nat_type}");
+            }
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::NatType(
+                nat_type,
+            ))));
+        }
+        (Timer::NatProbe(_) | Timer::NatVerdict(_), _) => {}
+        (Timer::ForgetClient(uid), ConnectionState::Host(host)) => {
+            let now = Instant::now();
+            host.suspended
+                .retain(|(client, until)| client.uid != uid || *until > now);
+        }
+        (Timer::ForgetClient(_), _) => {}
+        (Timer::ShareRoster, ConnectionState::Host(host)) => {
+            share_roster(host, send_socket);
+            timers.schedule(ROSTER_INTERVAL, Timer::ShareRoster);
+        }
+        (Timer::ShareRoster, _) => {}
+        (Timer::ReadyCheckTimeout(id), ConnectionState::Host(host)) => {
+            if let Some(check) = host.ready_check.as_mut().filter(|check| check.id == id) {
+                log::info!("the ready check timed out");
+
+                check.open = false;
+                send_ready_tally(host, send_tf2);
+            }
+        }
+        (Timer::ModCheckTimeout(id), ConnectionState::Host(host)) => {
+            if host
+                .mod_check
+                .as_ref()
+                .is_some_and(|check| check.id == id && check.open)
+            {
+                log::info!("the mod check timed out");
+
+                finish_mod_check(host, send_socket, timers, send_tf2);
+            }
+        }
+        (Timer::ModCheckTimeout(_), _) => {}
+        (Timer::Rebind, _) => {} // run_connections owns the socket
+        (Timer::SendOrder(uid), ConnectionState::Host(host)) => send_order(host, uid, send_socket),
+        (Timer::SendOrder(_), _) => {}
+        (Timer::ReadyCheckTimeout(id), ConnectionState::User(user)) => {
+            if user.ready_prompt == Some(id) {
+                user.ready_prompt = None;
+
+                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                    LocalMessage::ReadyPrompt(None),
+                )));
+            }
+        }
+    }
+}
+
+/// sends the order to everyone, spaced out when the server can't fit the whole party
+fn give_order(
+    host: &mut Host,
+    order: Order,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    let (free_slots, spacing) = match &order {
+        Order::JoinServer { free_slots, .. } => (*free_slots, JOIN_SPACING),
+        Order::LeaveServer => (None, Duration::ZERO),
+        Order::NoOrder => {
+            log::warn!("there is nothing to send");
+            return;
+        }
+    };
+
+    log::info!("sending order : {order:?}");
+
+    if host.staged_order.take().is_some() {
+        _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+            LocalMessage::OrderStaged(false),
+        )));
+    }
+
+    host.last_order = order;
+    host.clients
+        .iter_mut()
+        .for_each(|client| client.order_status = None);
+    timers.cancel(|timer| matches!(timer, Timer::SendOrder(_)));
+
+    let members = host
+        .clients
+        .iter()
+        .map(|client| client.uid)
+        .collect::<Vec<_>>();
+    let schedule = schedule_joins(&members, &host.join_priority, free_slots, spacing);
+
+    for (uid, delay) in schedule.joins {
+        if delay.is_zero() {
+            send_order(host, uid, send_socket);
+        } else {
+            timers.schedule(delay, Timer::SendOrder(uid));
+        }
+    }
+
+    if !schedule.waiting.is_empty() {
+        log::info!(
+            "the server only fits {} more; {} will wait for a free slot",
+            free_slots.unwrap_or_default(),
+            schedule.waiting.len()
+        );
+    }
+
+    for client in host
+        .clients
+        .iter_mut()
+        .filter(|client| schedule.waiting.contains(&client.uid))
+    {
+        client.order_status = Some(OrderStatus::WaitingForSlot);
+
+        match PacketMessage::RelayedChat(
+            client.uid,
+            "party".to_string(),
+            "the server is full; you'll follow when a slot frees up".to_string(),
+        )
+        .send()
+        .try_into()
+        {
+            Ok(packet) => _ = send_socket.send(Packet::reliable_ordered(client.addr, packet, None)),
+            Err(err) => log::warn!("failed to build chat packet {err}"),
+        }
+    }
+    host.join_queue = schedule.waiting.into();
+
+    send_order_statuses(host, send_tf2);
+}
+
+fn start_mod_check(
+    host: &mut Host,
+    required_mods: Vec<RequiredMod>,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    let id = host
+        .mod_check
+        .as_ref()
+        .map(|check| check.id.wrapping_add(1))
+        .unwrap_or_default();
+
+    log::info!(
+        "checking {} required mods with the party",
+        required_mods.len()
+    );
+
+    host.mod_check = Some(ModCheck {
+        id,
+        answers: host
+            .clients
+            .iter()
+            .map(|client| (client.uid, None))
+            .collect(),
+        open: true,
+    });
+
+    broadcast(host, send_socket, |uid| {
+        PacketMessage::Preflight(uid, id, required_mods.clone())
+    });
+
+    timers.cancel(|timer| matches!(timer, Timer::ModCheckTimeout(_)));
+    timers.schedule(MOD_CHECK_TIMEOUT, Timer::ModCheckTimeout(id));
+
+    send_mod_check_report(host, send_tf2);
+}
+
+/// sends the staged order on its own only if everyone still in the party can join
+fn finish_mod_check(
+    host: &mut Host,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    let Some(check) = host.mod_check.as_mut() else {
+        return;
+    };
+
+    check.open = false;
+    let id = check.id;
+    let clean = check
+        .answers
+        .iter()
+        .filter(|(uid, _)| host.clients.iter().any(|client| client.uid == *uid))
+        .all(|(_, problems)| {
+            problems
+                .as_ref()
+                .is_some_and(|problems| problems.is_empty())
+        });
+
+    timers.cancel(|timer| *timer == Timer::ModCheckTimeout(id));
+    send_mod_check_report(host, send_tf2);
+
+    match host.staged_order.take() {
+        Some(order) if clean => give_order(host, order, send_socket, timers, send_tf2),
+        Some(order) => {
+            log::info!("not everyone can join this server; send the order anyway with p_send_order or pick another server");
+            host.staged_order = Some(order);
+        }
+        None => {}
+    }
+}
+
+fn relay_chat(
+    host: &Host,
+    sender: String,
+    mut text: String,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    text = text.chars().filter(|c| !c.is_control()).collect();
+    if let Some((cut, _)) = text.char_indices().nth(MAX_CHAT_LEN) {
+        text.truncate(cut);
+    }
+
+    if text.trim().is_empty() {
+        return;
+    }
+
+    for client in host.clients.iter() {
+        match PacketMessage::RelayedChat(client.uid, sender.clone(), text.clone())
+            .send()
+            .try_into()
+        {
+            Ok(packet) => _ = send_socket.send(Packet::reliable_ordered(client.addr, packet, None)),
+            Err(err) => log::warn!("failed to build chat packet {err}"),
+        }
+    }
+
+    _ = send_tf2.send(LocalMessage::ChatMessage(sender, text));
+}
+
+fn send_order(host: &Host, uid: PlayerUid, send_socket: &crossbeam_channel::Sender<Packet>) {
+    let Some(client) = host.clients.iter().find(|client| client.uid == uid) else {
+        return;
+    };
+
+    match order_message(client, &host.last_order).send().try_into() {
+        Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(client.addr, packet)),
+        Err(err) => log::warn!("failed to build order packet {err}"),
+    }
+}
+
+/// the server password never goes out in the clear
+fn order_message(client: &Client, order: &Order) -> PacketMessage {
+    let mut order = order.clone();
+
+    match &mut order {
+        Order::JoinServer { password, .. } if !password.is_empty() => {
+            let sealed = client
+                .session_key
+                .as_ref()
+                .and_then(|key| key.seal(password));
+
+            if sealed.is_none() {
+                log::warn!(
+                    "couldn't share the server password with {}",
+                    client.info.display_name()
+                );
+            }
+
+            password.clear();
+            PacketMessage::NewOrder(client.uid, order, sealed)
+        }
+        _ => PacketMessage::NewOrder(client.uid, order, None),
+    }
+}
+
+fn report_order_status(
+    user: &User,
+    status: OrderStatus,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+) {
+    let Some(server) = user.server else {
+        return;
+    };
+
+    match PacketMessage::OrderStatus(user.uid, status)
+        .send()
+        .try_into()
+    {
+        Ok(packet) => _ = send_socket.send(Packet::reliable_ordered(server, packet, None)),
+        Err(err) => log::warn!("failed to build order status packet {err}"),
+    }
+}
+
+fn send_order_statuses(host: &Host, send_tf2: &Sender<LocalMessage>) {
+    let statuses = host
+        .clients
+        .iter()
+        .filter_map(|client| Some((client.uid.iter().collect(), client.order_status.clone()?)))
+        .collect();
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::OrderStatuses(statuses),
+    )));
+}
+
+fn send_mod_check_report(host: &Host, send_tf2: &Sender<LocalMessage>) {
+    let Some(check) = host.mod_check.as_ref() else {
+        return;
+    };
+
+    let report = check
+        .answers
+        .iter()
+        .map(|(uid, problems)| {
+            let name = host
+                .clients
+                .iter()
+                .find(|client| client.uid == *uid)
+                .map(|client| client.info.display_name())
+                .unwrap_or_else(|| uid.iter().collect());
+
+            (name, problems.clone())
+        })
+        .collect();
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::ModCheckReport(report, check.open),
+    )));
+}
+
+fn send_ready_tally(host: &Host, send_tf2: &Sender<LocalMessage>) {
+    let Some(check) = host.ready_check.as_ref() else {
+        return;
+    };
+
+    let answers = check
+        .answers
+        .iter()
+        .map(|(uid, answer)| {
+            let name = host
+                .clients
+                .iter()
+                .chain(host.suspended.iter().map(|(client, _)| client))
+                .find(|client| client.uid == *uid)
+                .map(|client| client.info.display_name())
+                .unwrap_or_else(|| uid.iter().collect());
+
+            (name, *answer)
+        })
+        .collect();
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::ReadyTally(answers, check.open),
+    )));
+}
+
+fn member_matches(client: &Client, member: &str) -> bool {
+    client.uid.iter().collect::<String>() == member
+        || client.info.name.eq_ignore_ascii_case(member)
+        || client
+            .info
+            .nickname
+            .as_deref()
+            .is_some_and(|nickname| nickname.eq_ignore_ascii_case(member))
+}
+
+/// `member` can be a uid or a name; banning also keeps them from joining again until the lobby closes
+fn kick_member(
+    host: &mut Host,
+    member: &str,
+    reason: String,
+    ban: bool,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    let matches = |client: &Client| member_matches(client, member);
+
+    let client = if let Some(i) = host.clients.iter().position(matches) {
+        host.clients.swap_remove(i)
+    } else if let Some(i) = host
+        .suspended
+        .iter()
+        .position(|(client, _)| matches(client))
+    {
+        host.suspended.swap_remove(i).0
+    } else {
+        log::warn!("there is no member called {member} in the party");
+        return;
+    };
+
+    let reason = match reason.trim() {
+        "" if ban => "banned by the host".to_string(),
+        "" => "kicked by the host".to_string(),
+        reason => reason.chars().filter(|c| !c.is_control()).collect(),
+    };
+    let name = client.info.display_name();
+
+    log::info!("{} {name}: {reason}", if ban { "banned" } else { "kicked" });
+
+    match PacketMessage::Kicked(client.uid, reason.clone(), ban)
+        .send()
+        .try_into()
+    {
+        Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(client.addr, packet)),
+        Err(err) => log::warn!("failed to build kick packet {err}"),
+    }
+
+    timers.cancel(|timer| matches!(timer, Timer::Ping(addr, _) if *addr == client.addr));
+    timers.cancel(|timer| *timer == Timer::ForgetClient(client.uid));
+
+    if ban {
+        host.bans.push(Ban {
+            uid: client.uid,
+            addr: client.addr,
+            reason: reason.clone(),
+        });
+    }
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::DroppedConnection(client.uid.iter().collect()),
+    )));
+    broadcast(host, send_socket, |uid| {
+        PacketMessage::MemberLeft(uid, client.uid)
+    });
+    rotate_transfer_token(host, send_socket);
+
+    relay_chat(
+        host,
+        "party".to_string(),
+        format!(
+            "{name} was {} ({reason})",
+            if ban { "banned" } else { "kicked" }
+        ),
+        send_socket,
+        send_tf2,
+    );
+}
+
+/// tells everyone else about the member and the member about everyone else
+fn announce_member(
+    host: &Host,
+    uid: PlayerUid,
+    info: &MemberInfo,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+) {
+    broadcast(host, send_socket, |to| {
+        PacketMessage::MemberJoined(to, uid, info.clone())
+    });
+
+    let Some(addr) = host
+        .clients
+        .iter()
+        .find(|client| client.uid == uid)
+        .map(|client| client.addr)
+    else {
+        return;
+    };
+
+    let host_info = crate::PLUGIN.wait().profile.lock().clone();
+    std::iter::once((host.uid, host_info))
+        .chain(
+            host.clients
+                .iter()
+                .map(|client| (client.uid, client.info.clone())),
+        )
+        .filter(|(member, _)| *member != uid)
+        .filter_map(|(member, info)| {
+            PacketMessage::MemberJoined(uid, member, info)
+                .send()
+                .try_into()
+                .map_err(|err| log::warn!("failed to build member packet {err}"))
+                .ok()
+        })
+        .for_each(|packet| _ = send_socket.send(Packet::reliable_ordered(addr, packet, None)));
+}
+
+fn broadcast(
+    host: &Host,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    msg: impl Fn(PlayerUid) -> PacketMessage,
+) {
+    for client in host.clients.iter() {
+        match msg(client.uid).send().try_into() {
+            Ok(packet) => _ = send_socket.send(Packet::reliable_ordered(client.addr, packet, None)),
+            Err(err) => log::warn!("failed to build packet {err}"),
+        }
+    }
+}
+
+fn auth_throttled(host: &mut Host, ip: IpAddr) -> bool {
+    let now = Instant::now();
+    host.failed_auths
+        .retain(|(_, at)| now.duration_since(*at) < FAILED_AUTH_WINDOW);
+
+    host.failed_auths
+        .iter()
+        .filter(|(failed, _)| *failed == ip)
+        .count()
+        >= MAX_FAILED_AUTHS
+}
+
+/// only registered lobbies get a link since it needs the matchmaking server
+fn share_party_link(host: &Host, send_tf2: &Sender<LocalMessage>) {
+    let (Some(lobby_id), Some(invite)) = (host.lobby_id, host.invite) else {
+        return;
+    };
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::PartyLink(lobby_link(
+            &lobby_id.iter().collect::<String>(),
+            &invite.iter().collect::<String>(),
+            host.matchmaking,
+        )),
+    )));
+}
+
+/// LAN parties never ask the matchmaking server for one; also makes invites
+fn new_lobby_id() -> LobbyUid {
+    nanoid::nanoid!(8)
+        .chars()
+        .collect::<Vec<char>>()
+        .try_into()
+        .expect("can't fail to collect a 8 len vec into a 8 len array")
+}
+
+/// skips the matchmaking server; the host answers vibe checks from anyone
+fn connect_directly(
+    user: &mut User,
+    addr: SocketAddr,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+) {
+    user.connect_to = Some(addr);
+
+    match PacketMessage::VibeCheck.send().try_into() {
+        Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(addr, packet)),
+        Err(err) => log::warn!("failed to build vibe check packet {err}"),
+    }
+}
+
+fn new_player_uid() -> PlayerUid {
+    nanoid::nanoid!(5)
+        .chars()
+        .collect::<Vec<char>>()
+        .try_into()
+        .expect("can't fail to collect a 5 len vec into a 5 len array")
+}
+
+/// sends every member the roster they need to pick a new host if we vanish
+fn share_roster(host: &Host, send_socket: &crossbeam_channel::Sender<Packet>) {
+    let Some(transfer_token) = host.transfer_token else {
+        return;
+    };
+
+    let mut roster = Roster {
+        lobby_id: host.lobby_id,
+        matchmaking: host.matchmaking,
+        transfer_token: None,
+        members: host
+            .clients
+            .iter()
+            .map(|client| (client, false))
+            .chain(host.suspended.iter().map(|(client, _)| (client, true)))
+            .map(|(client, suspended)| RosterMember {
+                uid: client.uid,
+                resume_verifier: client.resume_verifier,
+                info: client.info.clone(),
+                suspended,
+            })
+            .collect(),
+        settings: LobbySettings {
+            password: host.password,
+            invite: host.invite,
+            max_size: host.max_size,
+            locked: host.locked,
+            bans: host.bans.clone(),
+            join_priority: host.join_priority.clone(),
+        },
+    };
+    let successor = roster.successor();
+
+    for client in host.clients.iter() {
+        roster.transfer_token = (Some(client.uid) == successor).then_some(transfer_token);
+
+        match PacketMessage::Roster(client.uid, roster.clone())
+            .send()
+            .try_into()
+        {
+            Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(client.addr, packet)),
+            Err(err) => log::warn!("failed to build roster packet {err}"),
+        }
+    }
+}
+
+/// whoever left might have been the successor and still holds the token
+fn rotate_transfer_token(host: &mut Host, send_socket: &crossbeam_channel::Sender<Packet>) {
+    let (Some(lobby_id), Some(matchmaking)) = (host.lobby_id, host.matchmaking) else {
+        share_roster(host, send_socket);
+        return;
+    };
+
+    // nobody should be able to reclaim with the old token while we wait for the new one
+    host.transfer_token = None;
+
+    match PacketMessage::RotateTransferToken(lobby_id)
+        .send()
+        .try_into()
+    {
+        Ok(packet) => _ = send_socket.send(Packet::reliable_unordered(matchmaking, packet)),
+        Err(err) => log::warn!("failed to build transfer token packet {err}"),
+    }
+}
+
+/// the matchmaking server told us the host is gone; the lowest uid in the roster becomes the new host
+fn take_over_lobby(
+    lobby_id: LobbyUid,
+    state: &mut ConnectionState,
+    send_socket: &crossbeam_channel::Sender<Packet>,
+    timers: &mut Timers,
+    stun_addr: SocketAddr,
+    send_tf2: &Sender<LocalMessage>,
+) -> Result<(), PartyaError> {
+    let ConnectionState::User(user) = state else {
+        return Ok(());
+    };
+
+    if user.lobby_id != Some(lobby_id) || user.server.is_some() {
+        return Ok(());
+    }
+
+    let Some(roster) = user.roster.clone() else {
+        log::warn!("the host left before sharing the roster; can't migrate");
+        give_up_lobby(user, send_tf2);
+        return Ok(());
+    };
+
+    let Some(successor) = roster.successor() else {
+        give_up_lobby(user, send_tf2);
+        return Ok(());
+    };
+
+    if successor != user.uid {
+        log::info!(
+            "host left; waiting for {} to take over",
+            successor.iter().collect::<String>()
+        );
+
+        return Ok(()); // the reconnect timer keeps looking for the new host
+    }
+
+    let Some(transfer_token) = roster.transfer_token else {
+        log::warn!("the host never gave us the transfer token; can't migrate");
+        give_up_lobby(user, send_tf2);
+        return Ok(());
+    };
+
+    log::info!("host left; taking over the lobby");
+
+    let until = Instant::now() + RESUME_GRACE;
+    let suspended = roster
+        .members
+        .iter()
+        .filter(|member| member.uid != user.uid)
+        .map(|member| {
+            (
+                Client {
+                    addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+                    uid: member.uid,
+                    resume_verifier: member.resume_verifier,
+                    info: member.info.clone(),
+                    link: LinkTracker::default(),
+                    order_status: None,
+                    session_key: None,
+                },
+                until,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    timers.clear();
+    for (client, _) in suspended.iter() {
+        timers.schedule(RESUME_GRACE, Timer::ForgetClient(client.uid));
+    }
+
+    *state = ConnectionState::Host(Host {
+        uid: user.uid,
+        lobby_id: Some(lobby_id),
+        password: roster.settings.password,
+        invite: roster.settings.invite,
+        last_order: user.cached_order.clone(),
+        suspended,
+        matchmaking: Some(stun_addr),
+        max_size: roster.settings.max_size,
+        locked: roster.settings.locked,
+        bans: roster.settings.bans,
+        join_priority: roster.settings.join_priority,
+        ..Default::default()
+    });
+
+    _ = send_socket.send(Packet::reliable_unordered(
+        stun_addr,
+        PacketMessage::ReclaimLobby(lobby_id, transfer_token)
+            .send()
+            .try_into()?,
+    ));
+
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::Reconnecting(false),
+    )));
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
+        true,
+    ))));
+
+    Ok(())
+}
+
+fn start_pinging(timers: &mut Timers, addr: SocketAddr, uid: Option<PlayerUid>) {
+    timers.cancel(|timer| matches!(timer, Timer::Ping(a, _) if *a == addr));
+    timers.schedule(ping_interval(), Timer::Ping(addr, uid));
+}
+
+fn ping_interval() -> Duration {
+    crate::PLUGIN.wait().settings.lock().ping_interval()
+}
+
+fn reconnect_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5))
+}
+
+/// resumes our place in the party we were in before the game restarted
+fn rejoin_last_party(
+    user: &mut User,
+    party: LastParty,
+    timers: &mut Timers,
+    send_tf2: &Sender<LocalMessage>,
+) {
+    let (Some(lobby_id), Some(uid), Some(resume_token)) =
+        (party.lobby_id(), party.uid(), party.resume_token())
+    else {
+        log::warn!("the saved party is broken; forgetting it");
+        last_party::forget();
+        return;
+    };
 
-                let client_disconnected = remove_from_host(host, &addr).into_iter().collect();
+    log::info!("rejoining the last party {}", party.lobby_id);
 
-                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                    LocalMessage::DroppedConnection(client_disconnected),
-                )));
+    user.lobby_id = Some(lobby_id);
+    user.uid = uid;
+    user.resume_token = Some(resume_token);
+    user.restored = true;
+    user.reconnect_attempts = 0;
+    timers.schedule(Duration::ZERO, Timer::Reconnect);
 
-                if addr == stun_addr {
-                    log::warn!("disconnected from stun server");
-                    host.lobby_id = None;
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::LobbyUid(Some(party.lobby_id.clone())),
+    )));
+    user.last_party = Some(party);
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::Reconnecting(true),
+    )));
+}
 
-                    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                        LocalMessage::LobbyUid(None),
-                    )));
+fn give_up_lobby(user: &mut User, send_tf2: &Sender<LocalMessage>) {
+    user.lobby_id = None;
+    user.resume_token = None;
+    user.connect_to = None;
+    user.reconnect_attempts = 0;
+    user.restored = false;
+    user.direct = None;
+    user.members.clear();
+    user.last_party = None;
+    last_party::forget();
 
-                    if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
-                        unsafe { invite_hanlder.copy().clear_secret() };
-                    }
-                }
-            }
-        }
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::Reconnecting(false),
+    )));
+    _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+        LocalMessage::LobbyUid(None),
+    )));
+
+    if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
+        unsafe { invite_hanlder.copy().clear_secret() };
     }
 }
 
@@ -292,13 +1989,29 @@ fn process_message_host(
     msg: PacketMessage,
     send_socket: &crossbeam_channel::Sender<Packet>,
     state: &mut Host,
+    timers: &mut Timers,
     send_tf2: &Sender<LocalMessage>,
 ) -> Result<(), PartyaError> {
-    let conn = state.clients.iter().find(|(a, _)| addr == *a);
+    let conn = state.clients.iter().find(|client| addr == client.addr);
 
     match (msg, conn) {
-        (PacketMessage::Auth(password), None) => {
-            if password != state.password {
+        (PacketMessage::Auth(password, info, public_key), None) => {
+            if auth_throttled(state, addr.ip()) {
+                log::info!("rejected {addr}: too many wrong passwords");
+
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::AuthRejected(AuthRejection::Throttled)
+                        .send()
+                        .try_into()?,
+                ));
+
+                return Ok(());
+            }
+
+            if password != state.password && Some(password) != state.invite {
+                state.failed_auths.push((addr.ip(), Instant::now()));
+
                 _ = send_socket.send(Packet::reliable_unordered(
                     addr,
                     PacketResponse::FailedAuth.send().try_into()?,
@@ -307,39 +2020,237 @@ fn process_message_host(
                 return Ok(());
             }
 
+            // suspended members still have a spot since they can resume
+            let party_size = state.clients.len() + state.suspended.len() + 1;
+            let rejection = if let Some(ban) = state.bans.iter().find(|ban| ban.covers(None, addr))
+            {
+                Some(AuthRejection::Banned(ban.reason.clone()))
+            } else if state.locked {
+                Some(AuthRejection::Locked)
+            } else if party_size >= state.max_size as usize {
+                Some(AuthRejection::Full(state.max_size))
+            } else {
+                None
+            };
+
+            if let Some(rejection) = rejection {
+                log::info!("rejected {addr}: {rejection}");
+
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::AuthRejected(rejection).send().try_into()?,
+                ));
+
+                return Ok(());
+            }
+
             log::info!("{addr} authenticated with lobby");
 
-            let id = nanoid::nanoid!(5)
+            let id = new_player_uid();
+            let resume_token = nanoid::nanoid!(16)
                 .chars()
                 .collect::<Vec<char>>()
                 .try_into()
-                .expect("can't fail to collect a 5 len vec into a 5 len array");
+                .expect("can't fail to collect a 16 len vec into a 16 len array");
 
-            state.clients.push((addr, id));
+            let info = info.sanitized();
+
+            state.clients.push(Client {
+                addr,
+                uid: id,
+                resume_verifier: resume_verifier(&resume_token),
+                info: info.clone(),
+                link: LinkTracker::default(),
+                order_status: None,
+                session_key: Some(state.keys.session_key(public_key, &password)),
+            });
+            start_pinging(timers, addr, Some(id));
 
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
-                PacketResponse::AuthAccepted(id, state.password)
+                PacketResponse::AuthAccepted(id, password, resume_token, state.keys.public())
                     .send()
                     .try_into()?,
             ));
 
             _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
-                LocalMessage::NewConnection(id.into_iter().collect()),
+                LocalMessage::NewConnection(id.into_iter().collect(), info.display_name()),
             )));
+
+            announce_member(state, id, &info, send_socket);
         }
-        (PacketMessage::GetLastOrder(uid), Some(conn)) => {
-            if uid != conn.1 {
-                return Err(PartyaError::IllegalUid(conn.1, conn.0));
+        (PacketMessage::Resume(uid, resume_token, public_key), _) => {
+            let now = Instant::now();
+            state.suspended.retain(|(_, until)| *until > now);
+
+            if let Some(ban) = state.bans.iter().find(|ban| ban.covers(Some(uid), addr)) {
+                log::info!("rejected {addr}: banned");
+
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::AuthRejected(AuthRejection::Banned(ban.reason.clone()))
+                        .send()
+                        .try_into()?,
+                ));
+
+                return Ok(());
             }
 
-            log::info!("user requested last order");
+            let verifier = resume_verifier(&resume_token);
+            if state
+                .clients
+                .iter()
+                .any(|client| client.uid == uid && client.resume_verifier == verifier)
+            {
+                // the old connection might still be alive; the member retries once it times out
+                log::info!("{addr} tried to resume a session that is still connected");
+                return Ok(());
+            }
+
+            let client = state
+                .suspended
+                .iter()
+                .position(|(client, _)| client.uid == uid && client.resume_verifier == verifier)
+                .map(|i| state.suspended.swap_remove(i).0);
+
+            let Some(mut client) = client else {
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::ResumeRejected.send().try_into()?,
+                ));
+
+                return Ok(());
+            };
+
+            log::info!("{addr} resumed their session");
+
+            client.addr = addr;
+            client.link = LinkTracker::default();
+            client.session_key = Some(state.keys.session_key(public_key, &resume_token));
+            let info = client.info.clone();
+            state.clients.push(client);
+            start_pinging(timers, addr, Some(uid));
+
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
-                PacketMessage::NewOrder(uid, state.last_order.clone())
+                PacketResponse::Resumed(uid, state.keys.public())
                     .send()
                     .try_into()?,
             ));
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::NewConnection(uid.into_iter().collect(), info.display_name()),
+            )));
+
+            announce_member(state, uid, &info, send_socket);
+        }
+        (PacketMessage::GetLastOrder(uid), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
+            }
+
+            log::info!("user requested last order");
+            _ = send_socket.send(Packet::reliable_unordered(
+                addr,
+                order_message(conn, &state.last_order).send().try_into()?,
+            ));
+        }
+        (PacketMessage::OrderStatus(uid, status), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
+            }
+
+            let status = status.sanitized();
+            log::info!("{} {status}", conn.info.display_name());
+
+            // pass the slot on to whoever is next in line
+            let failed = status.is_final() && status != OrderStatus::Connected;
+
+            if let Some(client) = state.clients.iter_mut().find(|client| client.uid == uid) {
+                client.order_status = Some(status);
+            }
+
+            if failed {
+                if let Some(next) = state.join_queue.pop_front() {
+                    if let Some(client) = state.clients.iter_mut().find(|client| client.uid == next)
+                    {
+                        log::info!("{} gets the free slot", client.info.display_name());
+                        client.order_status = None;
+                    }
+
+                    send_order(state, next, send_socket);
+                }
+            }
+
+            send_order_statuses(state, send_tf2);
+        }
+        (PacketMessage::PreflightResult(uid, id, problems), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
+            }
+
+            let Some(check) = state.mod_check.as_mut().filter(|check| check.id == id) else {
+                return Ok(());
+            };
+
+            let problems = problems
+                .into_iter()
+                .take(MAX_MOD_PROBLEMS)
+                .map(|problem| {
+                    problem
+                        .chars()
+                        .filter(|c| !c.is_control())
+                        .take(MAX_CHAT_LEN)
+                        .collect()
+                })
+                .collect();
+
+            if let Some((_, answer)) = check.answers.iter_mut().find(|(member, _)| *member == uid) {
+                *answer = Some(problems);
+            }
+
+            let answered = check.answers.iter().all(|(member, answer)| {
+                answer.is_some() || !state.clients.iter().any(|client| client.uid == *member)
+            });
+
+            if check.open && answered {
+                finish_mod_check(state, send_socket, timers, send_tf2);
+            } else {
+                send_mod_check_report(state, send_tf2);
+            }
+        }
+        (PacketMessage::PartyChat(uid, text), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
+            }
+
+            relay_chat(state, conn.info.display_name(), text, send_socket, send_tf2);
+        }
+        (PacketMessage::ReadyAnswer(uid, id, ready), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
+            }
+
+            let Some(check) = state
+                .ready_check
+                .as_mut()
+                .filter(|check| check.id == id && check.open)
+            else {
+                return Ok(()); // answered too late
+            };
+
+            if let Some((_, answer)) = check.answers.iter_mut().find(|(member, _)| *member == uid) {
+                *answer = Some(ready);
+            }
+
+            if check.answers.iter().all(|(_, answer)| answer.is_some()) {
+                log::info!("everyone answered the ready check");
+
+                check.open = false;
+                timers.cancel(|timer| *timer == Timer::ReadyCheckTimeout(id));
+            }
+
+            send_ready_tally(state, send_tf2);
         }
         (PacketMessage::NewClient(addr), None) => {
             _ = send_socket.send(Packet::reliable_unordered(
@@ -347,18 +2258,22 @@ fn process_message_host(
                 PacketMessage::VibeCheck.send().try_into()?,
             ))
         }
-        (PacketMessage::Ping(Some(uid)), Some(conn)) => {
-            if uid != conn.1 {
-                return Err(PartyaError::IllegalUid(conn.1, conn.0));
+        (PacketMessage::Ping(Some(uid), seq), Some(conn)) => {
+            if uid != conn.uid {
+                return Err(PartyaError::IllegalUid(conn.uid, conn.addr));
             }
 
-            _ = send_socket.send(Packet::reliable_unordered(
+            _ = send_socket.send(Packet::unreliable(
                 addr,
-                PacketResponse::Pong.send().try_into()?,
+                PacketResponse::Pong(seq).send().try_into()?,
             ))
         } // should limit this
         (PacketMessage::VibeCheck, None) => {
-            // send back a vibe check; how bad can it be?
+            // anyone can ask, so addresses guessing passwords stop getting answers
+            if auth_throttled(state, addr.ip()) {
+                return Ok(());
+            }
+
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
                 PacketMessage::VibeCheck.send().try_into()?,
@@ -375,26 +2290,177 @@ fn process_message_user(
     msg: PacketMessage,
     send_socket: &crossbeam_channel::Sender<Packet>,
     state: &mut User,
+    timers: &mut Timers,
     send_tf2: &Sender<LocalMessage>,
 ) -> Result<(), PartyaError> {
     match msg {
-        PacketMessage::NewOrder(uid, order) if uid == state.uid => {
+        PacketMessage::NewOrder(uid, mut order, sealed)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::HasOrder(!matches!(order, Order::NoOrder)),
+            )));
+
+            if let Order::NoOrder = order {
+                log::info!("the host hasn't given an order yet");
+                state.cached_order = order;
+                return Ok(());
+            }
+
+            if let (Order::JoinServer { password, .. }, Some(sealed)) = (&mut order, sealed) {
+                match state.session_key.as_ref().and_then(|key| key.open(&sealed)) {
+                    Some(opened) => *password = opened,
+                    None => log::warn!("couldn't read the server password from the host"),
+                }
+            }
+
+            if !crate::PLUGIN.wait().settings.lock().auto_follow {
+                log::info!("the host gave an order; follow it with the repeat order button");
+
+                state.order_pending = false;
+                state.cached_order = order;
+                report_order_status(state, OrderStatus::Received, send_socket);
+                return Ok(());
+            }
+
             send_tf2
                 .send(LocalMessage::ExecuteOrder(order.clone()))
                 .expect("somehow a channel broke");
+
+            state.order_pending = matches!(order, Order::JoinServer { .. });
             state.cached_order = order;
+
+            report_order_status(state, OrderStatus::Received, send_socket);
         }
-        PacketMessage::Ping(Some(uid)) if uid == state.uid => {
-            _ = send_socket.send(Packet::reliable_unordered(
+        PacketMessage::RelayedChat(uid, sender, text)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            _ = send_tf2.send(LocalMessage::ChatMessage(sender, text));
+        }
+        PacketMessage::MemberJoined(uid, member, info)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            let info = info.sanitized();
+            let name = info.display_name();
+
+            match state.members.iter_mut().find(|(cmp, _)| *cmp == member) {
+                Some(known) => known.1 = info,
+                None => state.members.push((member, info)),
+            }
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::NewConnection(member.iter().collect(), name),
+            )));
+        }
+        PacketMessage::MemberLeft(uid, member)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            state.members.retain(|(cmp, _)| *cmp != member);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::DroppedConnection(member.iter().collect()),
+            )));
+        }
+        PacketMessage::ReadyCheck(uid, id, timeout)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            let timeout = Duration::from_secs(timeout as u64).min(READY_CHECK_TIMEOUT);
+
+            state.ready_prompt = Some(id);
+            timers.cancel(|timer| matches!(timer, Timer::ReadyCheckTimeout(_)));
+            timers.schedule(timeout, Timer::ReadyCheckTimeout(id));
+
+            _ = send_tf2.send(LocalMessage::ChatMessage(
+                "party".to_string(),
+                "the host started a ready check; answer with p_ready <0/1>".to_string(),
+            ));
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::ReadyPrompt(Some(Instant::now() + timeout)),
+            )));
+        }
+        PacketMessage::Preflight(uid, id, required_mods)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            state.preflight = Some(id);
+            _ = send_tf2.send(LocalMessage::PreflightMods(required_mods));
+        }
+        PacketMessage::Kicked(uid, reason, banned)
+            if uid == state.uid && Some(addr) == state.server =>
+        {
+            log::warn!(
+                "{} from the party: {reason}",
+                if banned { "banned" } else { "kicked" }
+            );
+
+            state.server = None;
+            state.roster = None;
+            state.members.clear();
+            state.ready_prompt = None;
+            timers.cancel(|timer| matches!(timer, Timer::Ping(a, _) if *a == addr));
+            timers.cancel(|timer| matches!(timer, Timer::Reconnect | Timer::ReadyCheckTimeout(_)));
+            give_up_lobby(state, send_tf2);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::Notice(
+                format!(
+                    "{} from the party: {reason}",
+                    if banned { "Banned" } else { "Kicked" }
+                ),
+            ))));
+        }
+        PacketMessage::Roster(uid, roster) if uid == state.uid && Some(addr) == state.server => {
+            // the host lost its matchmaking server and registered the lobby again
+            let moved = roster
+                .lobby_id
+                .filter(|lobby_id| state.lobby_id.is_some_and(|old| old != *lobby_id));
+
+            if let Some(lobby_id) = moved {
+                let lobby_id_string = lobby_id.iter().collect::<String>();
+                log::info!("the party moved to lobby {lobby_id_string}");
+
+                state.lobby_id = Some(lobby_id);
+                _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                    LocalMessage::LobbyUid(lobby_id_string.into()),
+                )));
+
+                if let Some(resume_token) = state.resume_token {
+                    let party = LastParty::new(
+                        lobby_id,
+                        &state.password,
+                        state.uid,
+                        resume_token,
+                        roster.matchmaking,
+                    );
+                    last_party::save(&party);
+                    state.last_party = Some(party);
+                }
+            } else if let Some(party) = state.last_party.as_mut() {
+                last_party::touch(party);
+            }
+
+            state.roster = Some(Box::new(roster));
+        }
+        PacketMessage::Ping(Some(uid), seq) if uid == state.uid => {
+            _ = send_socket.send(Packet::unreliable(
                 addr,
-                PacketResponse::Pong.send().try_into()?,
+                PacketResponse::Pong(seq).send().try_into()?,
             ))
         }
         PacketMessage::VibeCheck if Some(addr) == state.connect_to => {
             if let Some(lobby_addr) = state.connect_to.take() {
+                let msg = match state.resume_token {
+                    Some(resume_token) => {
+                        PacketMessage::Resume(state.uid, resume_token, state.keys.public())
+                    }
+                    None => PacketMessage::Auth(
+                        state.password,
+                        crate::PLUGIN.wait().profile.lock().clone(),
+                        state.keys.public(),
+                    ),
+                };
+
                 _ = send_socket.send(Packet::reliable_unordered(
                     lobby_addr,
-                    PacketMessage::Auth(state.password).send().try_into()?,
+                    msg.send().try_into()?,
                 ));
             } else {
                 log::warn!("lobby was not present somehow in correct vibe check");
@@ -407,23 +2473,43 @@ fn process_message_user(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_response(
     addr: SocketAddr,
     response: PacketResponse,
     send_socket: &crossbeam_channel::Sender<Packet>,
     state: &mut ConnectionState,
-    send_ping: &Sender<(SocketAddr, Option<PlayerUid>)>,
+    nat: &mut NatProbe,
+    timers: &mut Timers,
     stun_server_addr: SocketAddr,
     send_tf2: &Sender<LocalMessage>,
 ) -> Result<(), PartyaError> {
     match (response, state) {
-        (PacketResponse::AuthAccepted(uid, password), ConnectionState::User(user))
-            if user.password == password && user.server.is_none() =>
-        {
+        (
+            PacketResponse::AuthAccepted(uid, password, resume_token, public_key),
+            ConnectionState::User(user),
+        ) if user.password == password && user.server.is_none() => {
             log::info!("authenticated with lobby");
 
             user.server = Some(addr);
             user.uid = uid;
+            user.members.clear();
+            user.resume_token = Some(resume_token);
+            user.session_key = Some(user.keys.session_key(public_key, &user.password));
+            user.restored = false;
+            timers.cancel(|timer| *timer == Timer::ConnectTimeout);
+
+            if let Some(lobby_id) = user.lobby_id {
+                let party = LastParty::new(
+                    lobby_id,
+                    &user.password,
+                    uid,
+                    resume_token,
+                    Some(stun_server_addr),
+                );
+                last_party::save(&party);
+                user.last_party = Some(party);
+            }
 
             log::info!("featching last order");
             _ = send_socket.send(Packet::reliable_unordered(
@@ -431,10 +2517,84 @@ fn process_response(
                 PacketMessage::GetLastOrder(user.uid).send().try_into()?,
             ));
 
-            _ = send_ping.send((addr, Some(uid)));
+            user.server_link = LinkTracker::default();
+            start_pinging(timers, addr, Some(uid));
+        }
+        (PacketResponse::Resumed(uid, public_key), ConnectionState::User(user))
+            if user.uid == uid && user.server.is_none() =>
+        {
+            let Some(resume_token) = user.resume_token else {
+                log::warn!("got resumed without asking to");
+                return Ok(());
+            };
+
+            log::info!("reconnected to the lobby");
+
+            user.server = Some(addr);
+            // the host might have changed
+            user.session_key = Some(user.keys.session_key(public_key, &resume_token));
+            user.reconnect_attempts = 0;
+            timers.cancel(|timer| *timer == Timer::Reconnect);
+
+            if let Some(party) = user.last_party.as_mut() {
+                last_party::touch(party);
+            }
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::Reconnecting(false),
+            )));
+
+            _ = send_socket.send(Packet::reliable_unordered(
+                addr,
+                PacketMessage::GetLastOrder(user.uid).send().try_into()?,
+            ));
+
+            user.server_link = LinkTracker::default();
+            start_pinging(timers, addr, Some(uid));
+        }
+        (PacketResponse::ResumeRejected, ConnectionState::User(user)) if user.restored => {
+            log::warn!("the last party forgot about us; it has to be joined with its password");
+
+            timers.cancel(|timer| matches!(timer, Timer::ConnectTimeout | Timer::Reconnect));
+            give_up_lobby(user, send_tf2);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::Notice(
+                "Your last party forgot about you; join it again with its password".to_string(),
+            ))));
+        }
+        (PacketResponse::ResumeRejected, ConnectionState::User(user)) => {
+            log::warn!("the lobby forgot about us; authenticating again");
+
+            user.resume_token = None;
+            _ = send_socket.send(Packet::reliable_unordered(
+                addr,
+                PacketMessage::Auth(
+                    user.password,
+                    crate::PLUGIN.wait().profile.lock().clone(),
+                    user.keys.public(),
+                )
+                .send()
+                .try_into()?,
+            ));
         }
         (PacketResponse::FailedAuth, ConnectionState::User(user)) => {
-            log::error!("failed to authenticate with lobby {:?}", user.server)
+            log::error!("failed to authenticate with lobby {:?}", user.server);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::Notice(
+                "Wrong lobby password".to_string(),
+            ))));
+        }
+        (PacketResponse::AuthRejected(rejection), ConnectionState::User(user))
+            if user.server.is_none() && user.in_party() =>
+        {
+            log::error!("couldn't join the party: {rejection}");
+
+            timers.cancel(|timer| matches!(timer, Timer::ConnectTimeout | Timer::Reconnect));
+            give_up_lobby(user, send_tf2);
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::Notice(
+                format!("Couldn't join: {rejection}"),
+            ))));
         }
         (PacketResponse::FoundLobby(lobby_addr), ConnectionState::User(user)) => {
             log::info!("found lobby waiting for vibecheck; if this takes too long conisder complaining to catornot or try again pls");
@@ -446,46 +2606,97 @@ fn process_response(
 
             user.connect_to = Some(lobby_addr);
         }
-        (PacketResponse::NoLobby(lobby_id), ConnectionState::User(_)) => {
+        (PacketResponse::LobbyOrphaned(lobby_id), state) => take_over_lobby(
+            lobby_id,
+            state,
+            send_socket,
+            timers,
+            stun_server_addr,
+            send_tf2,
+        )?,
+        (PacketResponse::NoLobby(lobby_id), state @ ConnectionState::Host(_)) => {
+            log::warn!(
+                "couldn't take over lobby {}; the party is gone",
+                lobby_id.into_iter().collect::<String>()
+            );
+
+            *state = ConnectionState::User(User::default());
+            timers.clear();
+
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(LocalMessage::IsHost(
+                false,
+            ))));
+            _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
+                LocalMessage::LobbyUid(None),
+            )));
+        }
+        (PacketResponse::NoLobby(lobby_id), ConnectionState::User(user)) => {
             log::info!(
                 "failed to find lobby {}",
                 lobby_id.into_iter().collect::<String>()
             );
 
+            if user.resume_token.is_some() && user.lobby_id == Some(lobby_id) {
+                // the host might be coming back too; the reconnect timer will retry
+                return Ok(());
+            }
+
+            user.lobby_id = None;
             _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
                 LocalMessage::LobbyUid(None),
             )));
         }
-        (PacketResponse::CreatedLobby(lobby_id), ConnectionState::Host(host)) => {
+        (PacketResponse::CreatedLobby(lobby_id, transfer_token), ConnectionState::Host(host)) => {
             host.lobby_id = Some(lobby_id);
+            host.transfer_token = Some(transfer_token);
+            host.matchmaking = Some(addr);
             let lobby_id = lobby_id.into_iter().collect::<String>();
 
             log::info!("created a lobby {}", lobby_id);
 
-            _ = send_ping.send((addr, None));
+            host.stun_link = LinkTracker::default();
+            start_pinging(timers, addr, None);
+
+            share_roster(host, send_socket);
+            timers.cancel(|timer| *timer == Timer::ShareRoster);
+            timers.schedule(ROSTER_INTERVAL, Timer::ShareRoster);
 
             _ = send_tf2.send(LocalMessage::ForwardToGui(Box::new(
                 LocalMessage::LobbyUid(lobby_id.clone().into()),
             )));
 
+            host.invite.get_or_insert_with(new_lobby_id);
+            share_party_link(host, send_tf2);
+
             if let Some(invite_hanlder) = crate::PLUGIN.wait().invite_handler.get() {
-                if let Ok(lobby_id_cstring) = rrplug::mid::utils::try_cstring(&lobby_id) {
+                if let Ok(lobby_id_cstring) =
+                    rrplug::mid::utils::try_cstring(&lobby_secret(&lobby_id, addr))
+                {
                     _ = unsafe { invite_hanlder.copy().set_secret(lobby_id_cstring.as_ptr()) };
                 }
             }
         }
-        (PacketResponse::Pong, ConnectionState::Host(host)) => {
-            if let Some((_, uid)) = host.clients.iter().find(|(a, _)| a == &addr) {
-                _ = send_ping.send((addr, Some(*uid)));
+        (PacketResponse::TransferTokenRotated(transfer_token), ConnectionState::Host(host))
+            if host.matchmaking == Some(addr) =>
+        {
+            host.transfer_token = Some(transfer_token);
+            share_roster(host, send_socket);
+        }
+        (PacketResponse::Pong(seq), ConnectionState::Host(host)) => {
+            if let Some(client) = host.clients.iter_mut().find(|client| client.addr == addr) {
+                client.link.on_pong(seq, Instant::now());
             } else if addr == stun_server_addr {
-                _ = send_ping.send((addr, None));
+                host.stun_link.on_pong(seq, Instant::now());
             }
         } // pong comfirmed
-        (PacketResponse::Pong, ConnectionState::User(user)) => {
+        (PacketResponse::Pong(seq), ConnectionState::User(user)) => {
             if user.server == Some(addr) {
-                _ = send_ping.send((addr, Some(user.uid)));
+                user.server_link.on_pong(seq, Instant::now());
             }
         } // pong comfirmed
+        (PacketResponse::NatMapping(id, mapped, probe_port), _) => {
+            nat.record(addr, stun_server_addr, id, mapped, probe_port)
+        }
         (r, ConnectionState::User(_)) => {
             log::warn!("received a unexpected user response packet {r:?}")
         }
@@ -498,23 +2709,224 @@ fn process_response(
 }
 
 fn remove_from_host(host: &mut Host, addr: &SocketAddr) -> [char; 5] {
-    if let Some(i) = host.clients.iter().position(|(a, _)| a == addr) {
-        host.clients.swap_remove(i).1
+    if let Some(i) = host.clients.iter().position(|client| client.addr == *addr) {
+        host.clients.swap_remove(i).uid
     } else {
         Default::default()
     }
 }
 
-pub fn run_ping_thread(
-    send_socket: crossbeam_channel::Sender<Packet>,
-    recv_ping: Receiver<(SocketAddr, Option<PlayerUid>)>,
-) {
-    while let Ok(ping) = recv_ping.recv() {
-        wait(500);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compartya_shared::crypto::KeyPair;
+
+    const IDLE_WINDOW: Duration = Duration::from_secs(2);
+
+    /// user and system time of the whole process
+    #[cfg(target_os = "linux")]
+    fn cpu_time() -> Duration {
+        let stat = std::fs::read_to_string("/proc/self/stat").expect("procfs should be mounted");
+        // the process name can have spaces in it so count from after it
+        let fields = stat[stat.rfind(')').expect("stat has the process name") + 2..]
+            .split(' ')
+            .collect::<Vec<&str>>();
+        let ticks = |field: &str| field.parse::<u64>().expect("stat fields are numbers");
+
+        // USER_HZ is always 100
+        Duration::from_millis((ticks(fields[11]) + ticks(fields[12])) * 10)
+    }
+
+    /// user and system time of the whole process
+    #[cfg(windows)]
+    fn cpu_time() -> Duration {
+        use windows::Win32::{
+            Foundation::FILETIME,
+            System::Threading::{GetCurrentProcess, GetProcessTimes},
+        };
+
+        let (mut creation, mut exit, mut kernel, mut user) = (
+            FILETIME::default(),
+            FILETIME::default(),
+            FILETIME::default(),
+            FILETIME::default(),
+        );
+        unsafe {
+            GetProcessTimes(
+                GetCurrentProcess(),
+                &mut creation,
+                &mut exit,
+                &mut kernel,
+                &mut user,
+            )
+        }
+        .expect("the process can read its own times");
 
-        _ = send_socket.send(Packet::reliable_unordered(
-            ping.0,
-            PacketMessage::Ping(ping.1).send().try_into().unwrap(),
-        ))
+        let hundred_nanos =
+            |time: FILETIME| (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+        Duration::from_nanos((hundred_nanos(kernel) + hundred_nanos(user)) * 100)
+    }
+
+    #[test]
+    fn idle_loop_stays_asleep() {
+        let socket = Socket::bind("127.0.0.1:0").expect("loopback should have a free port");
+        let (_send_socket, recv_socket, mut poll_thread) = start_polling(socket);
+        let mut timers = Timers::default();
+        timers.schedule(IDLE_WINDOW * 10, Timer::ShareRoster);
+
+        let start = Instant::now();
+        let cpu_start = cpu_time();
+        let mut wakeups = 0;
+
+        while start.elapsed() < IDLE_WINDOW {
+            let timer = timers
+                .next_deadline()
+                .map(crossbeam_channel::at)
+                .unwrap_or_else(crossbeam_channel::never);
+
+            crossbeam_channel::select! {
+                recv(recv_socket) -> _ => wakeups += 1,
+                recv(timer) -> _ => wakeups += 1,
+                default(IDLE_WINDOW.saturating_sub(start.elapsed())) => {}
+            }
+        }
+
+        let used = cpu_time().saturating_sub(cpu_start);
+        poll_thread.stop();
+
+        assert_eq!(wakeups, 0, "nothing should wake an idle loop");
+        // the poll thread's millisecond naps cost a few percent, a busy loop would be all of it
+        assert!(
+            used < IDLE_WINDOW / 10,
+            "used {used:?} of cpu while idle for {IDLE_WINDOW:?}"
+        );
+    }
+
+    #[test]
+    fn wrong_passwords_throttle_the_address() {
+        let mut host = Host::default();
+        let guesser = IpAddr::from([10, 0, 0, 2]);
+        let other = IpAddr::from([10, 0, 0, 3]);
+
+        for _ in 0..MAX_FAILED_AUTHS - 1 {
+            host.failed_auths.push((guesser, Instant::now()));
+        }
+        assert!(!auth_throttled(&mut host, guesser));
+
+        host.failed_auths.push((guesser, Instant::now()));
+        assert!(auth_throttled(&mut host, guesser));
+        assert!(!auth_throttled(&mut host, other));
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let mut host = Host::default();
+        let guesser = IpAddr::from([10, 0, 0, 2]);
+        let Some(long_ago) = Instant::now().checked_sub(FAILED_AUTH_WINDOW * 2) else {
+            return; // the clock started too recently to look back that far
+        };
+
+        host.failed_auths = vec![(guesser, long_ago); MAX_FAILED_AUTHS];
+
+        assert!(!auth_throttled(&mut host, guesser));
+        assert!(host.failed_auths.is_empty());
+    }
+
+    #[test]
+    fn successor_keeps_the_password_and_invite() {
+        let lobby_id = ['l', 'o', 'b', 'b', 'y', 'i', 'd', '0'];
+        let password = ['h', 'u', 'n', 't', 'e', 'r', '2', ' '];
+        let invite = ['i', 'n', 'v', 'i', 't', 'e', '0', '1'];
+        let successor = ['a', 'a', 'a', 'a', 'a'];
+        let member = |uid| RosterMember {
+            uid,
+            resume_verifier: [0; 32],
+            info: MemberInfo::default(),
+            suspended: false,
+        };
+
+        // joined through a party link so it only ever knew the invite
+        let mut state = ConnectionState::User(User {
+            uid: successor,
+            password: invite,
+            lobby_id: Some(lobby_id),
+            roster: Some(Box::new(Roster {
+                lobby_id: Some(lobby_id),
+                matchmaking: None,
+                transfer_token: Some(['t'; 16]),
+                members: vec![member(successor), member(['b', 'b', 'b', 'b', 'b'])],
+                settings: LobbySettings {
+                    password,
+                    invite: Some(invite),
+                    max_size: 4,
+                    ..Default::default()
+                },
+            })),
+            ..Default::default()
+        });
+        let (send_socket, recv_socket) = crossbeam_channel::unbounded();
+        let (send_tf2, _recv_tf2) = std::sync::mpsc::channel();
+        let stun_addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+
+        take_over_lobby(
+            lobby_id,
+            &mut state,
+            &send_socket,
+            &mut Timers::default(),
+            stun_addr,
+            &send_tf2,
+        )
+        .unwrap();
+
+        let ConnectionState::Host(host) = state else {
+            panic!("the successor should be hosting");
+        };
+        assert_eq!(host.password, password);
+        assert_eq!(host.invite, Some(invite));
+        assert_eq!(host.max_size, 4);
+        assert_eq!(host.suspended.len(), 1);
+        assert_eq!(recv_socket.try_recv().unwrap().addr(), stun_addr);
+    }
+
+    #[test]
+    fn bans_cover_other_ports() {
+        let banned = SocketAddr::from(([10, 0, 0, 2], 40000));
+        let mut host = Host {
+            password: ['p'; 8],
+            max_size: 8,
+            bans: vec![Ban {
+                uid: ['b'; 5],
+                addr: banned,
+                reason: "cheating".to_string(),
+            }],
+            ..Default::default()
+        };
+        let (send_socket, recv_socket) = crossbeam_channel::unbounded();
+        let (send_tf2, _recv_tf2) = std::sync::mpsc::channel();
+        let comeback = SocketAddr::new(banned.ip(), 40001);
+
+        process_message_host(
+            comeback,
+            PacketMessage::Auth(
+                host.password,
+                MemberInfo::default(),
+                KeyPair::generate().public(),
+            ),
+            &send_socket,
+            &mut host,
+            &mut Timers::default(),
+            &send_tf2,
+        )
+        .unwrap();
+
+        let packet = recv_socket.try_recv().unwrap();
+        assert_eq!(packet.addr(), comeback);
+        assert!(matches!(
+            packet.payload().try_into(),
+            Ok(SentPacket::PacketResponse(PacketResponse::AuthRejected(
+                AuthRejection::Banned(_)
+            )))
+        ));
+        assert!(host.clients.is_empty());
     }
 }
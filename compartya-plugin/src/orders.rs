@@ -6,31 +6,72 @@ use rrplug::{
     prelude::*,
 };
 
-use crate::{bindings::ENGINE_FUNCTIONS, LocalMessage};
+use compartya_shared::{LobbyUid, Order, OrderStatus, RequiredMod};
+
+use crate::{
+    bindings::ENGINE_FUNCTIONS, launch_args::to_password, matchmaking::split_lobby_secret,
+    server_ranking::best_server, LocalMessage, ServerPassword,
+};
 
 #[derive(PushToSquirrelVm, GetFromSQObject, SQVMName, GetFromSquirrelVm)]
-struct RequiredModInfo {
-    name: String,
-    version: String,
-}
-
-#[derive(PushToSquirrelVm, SQVMName, GetFromSquirrelVm)]
-struct ServerInfo {
-    index: i32,
-    id: String,
-    name: String,
-    description: String,
-    map: String,
-    playlist: String,
-    player_count: i32,
-    max_player_count: i32,
-    requires_password: bool,
-    region: String,
-    required_mods: Vec<RequiredModInfo>,
+pub struct RequiredModInfo {
+    pub name: String,
+    pub version: String,
+}
+
+impl From<RequiredMod> for RequiredModInfo {
+    fn from(value: RequiredMod) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+        }
+    }
+}
+
+impl From<RequiredModInfo> for RequiredMod {
+    fn from(value: RequiredModInfo) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+        }
+    }
+}
+
+/// declared in force_join.gnut, the fields have to stay in the same order
+#[derive(PushToSquirrelVm, GetFromSQObject, SQVMName, GetFromSquirrelVm)]
+pub struct CompartyaMember {
+    pub uid: String,
+    pub name: String,
+}
+
+#[derive(PushToSquirrelVm, GetFromSQObject, SQVMName, GetFromSquirrelVm)]
+pub struct ServerInfo {
+    pub index: i32,
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub map: String,
+    pub playlist: String,
+    pub player_count: i32,
+    pub max_player_count: i32,
+    pub requires_password: bool,
+    pub region: String,
+    pub required_mods: Vec<RequiredModInfo>,
 }
 
 pub fn register_functions() {
     register_sq_functions(connected_to_server);
+    register_sq_functions(report_order_status);
+    register_sq_functions(set_server_password);
+    register_sq_functions(report_preflight);
+    register_sq_functions(rank_servers);
+    register_sq_functions(is_host);
+    register_sq_functions(get_lobby_id);
+    register_sq_functions(get_members);
+    register_sq_functions(host_lobby);
+    register_sq_functions(join_lobby);
+    register_sq_functions(leave);
+    register_sq_functions(issue_order);
     register_sq_functions(sq_log_error);
     register_sq_functions(sq_log_info);
 }
@@ -87,18 +128,247 @@ pub fn init_order_capture(handle: &CSquirrelVMHandle, _engine_token: EngineToken
 fn connected_to_server(server_info: ServerInfo) {
     log::info!("joined server : {}", server_info.name);
 
-    if server_info.requires_password {
-        log::warn!("people won't be able to join your server since it has a password");
-        // todo share the password
+    let password = if server_info.requires_password {
+        server_password_for(&server_info.id)
+    } else {
+        *crate::PLUGIN.wait().server_password.lock() = None;
+        None
+    };
+
+    if server_info.requires_password && password.is_none() {
+        log::warn!("this server has a password that compartya didn't catch; share it with p_server_password <password> or the party won't be able to join");
+    }
+
+    let required_mods = server_info
+        .required_mods
+        .into_iter()
+        .map(RequiredMod::from)
+        .collect::<Vec<_>>();
+    let order = Order::JoinServer {
+        id: server_info.id.clone(),
+        password: password.unwrap_or_default(),
+        required_mods,
+        // the server list was fetched before we took our own slot
+        free_slots: Some(
+            (server_info.max_player_count - server_info.player_count - 1).max(0) as u32,
+        ),
+    };
+    *crate::PLUGIN.wait().joined_server.lock() = Some(order.clone());
+
+    _ = crate::PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::StageOrder(order));
+
+    // only goes anywhere if we are following an order
+    _ = crate::PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::JoinedServer(server_info.id, server_info.name));
+}
+
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaReportOrderStatus")]
+fn report_order_status(status: String, detail: String) {
+    let status = match status.as_str() {
+        "searching" => OrderStatus::SearchingServers,
+        "not_found" => OrderStatus::ServerNotFound,
+        "missing_mods" => OrderStatus::MissingMods(detail),
+        "auth_failed" => OrderStatus::AuthFailed(detail),
+        "connecting" => OrderStatus::Connecting,
+        _ => {
+            log::warn!("unknown order status {status}");
+            return;
+        }
+    };
+
+    _ = crate::PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::ReportOrderStatus(status));
+}
+
+/// the answer to `CompartyaPreflightMods`; empty if we have everything
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaReportPreflight")]
+fn report_preflight(problems: Vec<String>) {
+    _ = crate::PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::PreflightResult(problems));
+}
+
+/// the server list `CompartyaFetchServers` got for the search in `PLUGIN.server_search`
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaRankServers")]
+fn rank_servers(servers: Vec<ServerInfo>, installed_mods: Vec<String>) {
+    let Some(criteria) = crate::PLUGIN.wait().server_search.lock().take() else {
+        return;
+    };
+
+    let send_runframe = &crate::PLUGIN.wait().send_runframe;
+
+    let Some(server) = best_server(&servers, &criteria, &installed_mods) else {
+        log::warn!(
+            "none of the {} servers fit the party of {}",
+            servers.len(),
+            criteria.party_size
+        );
+
+        _ = send_runframe.send(LocalMessage::ForwardToEngine(Box::new(
+            LocalMessage::ChatMessage(
+                "party".to_string(),
+                "couldn't find a server that fits the whole party".to_string(),
+            ),
+        )));
+        return;
+    };
+
+    log::info!(
+        "picked {} ({}/{}) out of {} servers",
+        server.name,
+        server.player_count,
+        server.max_player_count,
+        servers.len()
+    );
+
+    // the party follows once we are in through the usual connect callback
+    _ = send_runframe.send(LocalMessage::ForwardToEngine(Box::new(
+        LocalMessage::ExecuteOrder(Order::JoinServer {
+            id: server.id.clone(),
+            password: String::new(),
+            required_mods: server
+                .required_mods
+                .iter()
+                .map(|required| RequiredMod {
+                    name: required.name.clone(),
+                    version: required.version.clone(),
+                })
+                .collect(),
+            free_slots: None,
+        }),
+    )));
+}
+
+/// the saved password if it was entered for this server; one for another server is dropped
+pub fn server_password_for(id: &str) -> Option<String> {
+    let mut saved = crate::PLUGIN.wait().server_password.lock();
+    let password = saved.as_mut().and_then(|saved| {
+        let server = saved.server.get_or_insert_with(|| id.to_string());
+        (*server == id).then(|| saved.password.clone())
+    });
+
+    if password.is_none() {
+        *saved = None;
     }
 
+    password
+}
+
+/// called by the password menu right before we try to join with it
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaSetServerPassword")]
+fn set_server_password(password: String) {
+    *crate::PLUGIN.wait().server_password.lock() = ServerPassword::new(password);
+}
+
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaIsHost")]
+fn is_host() -> bool {
+    crate::PLUGIN.wait().party.lock().is_host
+}
+
+/// empty when we aren't in a party
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaGetLobbyId")]
+fn get_lobby_id() -> String {
+    crate::PLUGIN
+        .wait()
+        .party
+        .lock()
+        .lobby_id
+        .clone()
+        .unwrap_or_default()
+}
+
+/// everyone else in the party
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaGetMembers")]
+fn get_members() -> Vec<CompartyaMember> {
+    crate::PLUGIN
+        .wait()
+        .party
+        .lock()
+        .members
+        .iter()
+        .map(|(uid, name)| CompartyaMember {
+            uid: uid.clone(),
+            name: name.clone(),
+        })
+        .collect()
+}
+
+/// false if the password is longer than 8 chars
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaHostLobby")]
+fn host_lobby(password: String) -> bool {
+    let Some(password) = to_password(&password) else {
+        return false;
+    };
+
+    _ = crate::PLUGIN
+        .wait()
+        .send_runframe
+        .send(LocalMessage::BecomeHost(password));
+
+    true
+}
+
+/// takes a lobby id or `<lobby id>@<server>`; false if either doesn't fit
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaJoinLobby")]
+fn join_lobby(lobby_id: String, password: String) -> bool {
+    let (lobby_id, server) = split_lobby_secret(&lobby_id);
+    let lobby_id: Result<LobbyUid, _> = lobby_id.chars().collect::<Vec<char>>().try_into();
+
+    let (Ok(lobby_id), Some(password)) = (lobby_id, to_password(&password)) else {
+        return false;
+    };
+
+    let send_runframe = &crate::PLUGIN.wait().send_runframe;
+    _ = send_runframe.send(LocalMessage::Leave);
+    _ = send_runframe.send(LocalMessage::ConnectToLobby(lobby_id, password, server));
+
+    true
+}
+
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaLeave")]
+fn leave() {
+    _ = crate::PLUGIN.wait().send_runframe.send(LocalMessage::Leave);
+}
+
+/// sends the host to the server and the party follows through the connect callback; false for members
+#[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaIssueOrder")]
+fn issue_order(server: ServerInfo, password: String) -> bool {
+    if !crate::PLUGIN.wait().party.lock().is_host {
+        log::warn!("only the host can give the party orders");
+        return false;
+    }
+
+    *crate::PLUGIN.wait().server_password.lock() =
+        ServerPassword::new(password.clone()).map(|password| ServerPassword {
+            server: Some(server.id.clone()),
+            ..password
+        });
+
     _ = crate::PLUGIN
         .wait()
         .send_runframe
-        .send(LocalMessage::NewOrder(compartya_shared::Order::JoinServer(
-            server_info.id,
-            "".into(), // server_info.requires_password.then()
+        .send(LocalMessage::ForwardToEngine(Box::new(
+            LocalMessage::ExecuteOrder(Order::JoinServer {
+                id: server.id,
+                password,
+                required_mods: server
+                    .required_mods
+                    .into_iter()
+                    .map(RequiredMod::from)
+                    .collect(),
+                free_slots: None,
+            }),
         )));
+
+    true
 }
 
 #[rrplug::sqfunction(VM = "UI", ExportName = "CompartyaLogInfo")]
@@ -0,0 +1,226 @@
+use std::cmp::Reverse;
+
+use crate::orders::ServerInfo;
+
+/// what the party is looking for in a server; `None` matches anything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerCriteria {
+    pub playlist: Option<String>,
+    pub map: Option<String>,
+    pub region: Option<String>,
+    /// how many free slots the server needs, the host included
+    pub party_size: u32,
+    /// mods the server is allowed to require besides the Northstar ones
+    pub allowed_mods: Option<Vec<String>>,
+}
+
+/// the server the whole party should go to; `None` if nothing fits everyone
+pub fn best_server<'a>(
+    servers: &'a [ServerInfo],
+    criteria: &ServerCriteria,
+    installed_mods: &[String],
+) -> Option<&'a ServerInfo> {
+    servers
+        .iter()
+        .filter(|server| fits(server, criteria, installed_mods))
+        .max_by_key(|server| score(server))
+}
+
+pub fn fits(server: &ServerInfo, criteria: &ServerCriteria, installed_mods: &[String]) -> bool {
+    let matches = |wanted: &Option<String>, value: &str| {
+        wanted
+            .as_deref()
+            .is_none_or(|wanted| wanted.eq_ignore_ascii_case(value))
+    };
+
+    !server.requires_password
+        && server.max_player_count - server.player_count >= criteria.party_size as i32
+        && matches(&criteria.playlist, &server.playlist)
+        && matches(&criteria.map, &server.map)
+        && matches(&criteria.region, &server.region)
+        && server.required_mods.iter().all(|required| {
+            installed_mods.contains(&required.name)
+                && (required.name.starts_with("Northstar.")
+                    || criteria
+                        .allowed_mods
+                        .as_ref()
+                        .is_none_or(|allowed| allowed.contains(&required.name)))
+        })
+}
+
+/// busier servers are more fun, after that the less mods to load the better
+fn score(server: &ServerInfo) -> (i32, Reverse<usize>, Reverse<&str>) {
+    (
+        server.player_count,
+        Reverse(server.required_mods.len()),
+        Reverse(server.id.as_str()), // so everyone would pick the same one
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::RequiredModInfo;
+
+    fn server(id: &str, player_count: i32, max_player_count: i32) -> ServerInfo {
+        ServerInfo {
+            index: 0,
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            map: "mp_glitch".to_string(),
+            playlist: "aitdm".to_string(),
+            player_count,
+            max_player_count,
+            requires_password: false,
+            region: "EU".to_string(),
+            required_mods: Vec::new(),
+        }
+    }
+
+    fn with_mods(mut server: ServerInfo, mods: &[&str]) -> ServerInfo {
+        server.required_mods = mods
+            .iter()
+            .map(|name| RequiredModInfo {
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+            })
+            .collect();
+        server
+    }
+
+    fn party(party_size: u32) -> ServerCriteria {
+        ServerCriteria {
+            party_size,
+            ..Default::default()
+        }
+    }
+
+    fn installed(mods: &[&str]) -> Vec<String> {
+        mods.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn party_has_to_fit() {
+        assert!(fits(&server("a", 12, 16), &party(4), &[]));
+        assert!(!fits(&server("a", 13, 16), &party(4), &[]));
+        assert!(fits(&server("a", 16, 16), &party(0), &[]));
+        assert!(!fits(&server("a", 16, 16), &party(1), &[]));
+    }
+
+    #[test]
+    fn password_servers_are_skipped() {
+        let mut locked = server("a", 0, 16);
+        locked.requires_password = true;
+
+        assert!(!fits(&locked, &party(1), &[]));
+    }
+
+    #[test]
+    fn filters_ignore_case() {
+        let criteria = ServerCriteria {
+            playlist: Some("AITDM".to_string()),
+            map: Some("MP_Glitch".to_string()),
+            region: Some("eu".to_string()),
+            ..party(1)
+        };
+
+        assert!(fits(&server("a", 0, 16), &criteria, &[]));
+    }
+
+    #[test]
+    fn filters_have_to_match() {
+        let wrong_map = ServerCriteria {
+            map: Some("mp_forwardbase_kodai".to_string()),
+            ..party(1)
+        };
+        let wrong_playlist = ServerCriteria {
+            playlist: Some("ps".to_string()),
+            ..party(1)
+        };
+        let wrong_region = ServerCriteria {
+            region: Some("NA".to_string()),
+            ..party(1)
+        };
+
+        assert!(!fits(&server("a", 0, 16), &wrong_map, &[]));
+        assert!(!fits(&server("a", 0, 16), &wrong_playlist, &[]));
+        assert!(!fits(&server("a", 0, 16), &wrong_region, &[]));
+    }
+
+    #[test]
+    fn required_mods_have_to_be_installed() {
+        let modded = with_mods(server("a", 0, 16), &["Mod.A"]);
+
+        assert!(fits(&modded, &party(1), &installed(&["Mod.A"])));
+        assert!(!fits(&modded, &party(1), &installed(&["Mod.B"])));
+    }
+
+    #[test]
+    fn allowed_mods_dont_limit_northstar_ones() {
+        let criteria = ServerCriteria {
+            allowed_mods: Some(installed(&["Mod.A"])),
+            ..party(1)
+        };
+        let mods = installed(&["Mod.A", "Mod.B", "Northstar.Custom"]);
+
+        assert!(fits(
+            &with_mods(server("a", 0, 16), &["Mod.A", "Northstar.Custom"]),
+            &criteria,
+            &mods
+        ));
+        assert!(!fits(
+            &with_mods(server("a", 0, 16), &["Mod.B"]),
+            &criteria,
+            &mods
+        ));
+        // northstar mods still have to be installed
+        assert!(!fits(
+            &with_mods(server("a", 0, 16), &["Northstar.Coop"]),
+            &criteria,
+            &mods
+        ));
+    }
+
+    #[test]
+    fn busiest_server_wins() {
+        let servers = [server("a", 2, 16), server("b", 10, 16), server("c", 15, 16)];
+
+        assert_eq!(
+            best_server(&servers, &party(2), &[]).map(|server| server.id.as_str()),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn fewer_mods_break_ties() {
+        let mods = installed(&["Mod.A"]);
+        let servers = [
+            with_mods(server("a", 4, 16), &["Mod.A"]),
+            server("b", 4, 16),
+        ];
+
+        assert_eq!(
+            best_server(&servers, &party(2), &mods).map(|server| server.id.as_str()),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn lowest_id_breaks_remaining_ties() {
+        let servers = [server("c", 4, 16), server("a", 4, 16), server("b", 4, 16)];
+
+        assert_eq!(
+            best_server(&servers, &party(2), &[]).map(|server| server.id.as_str()),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn nothing_fits() {
+        let servers = [server("a", 15, 16)];
+
+        assert!(best_server(&servers, &party(2), &[]).is_none());
+        assert!(best_server(&[], &party(2), &[]).is_none());
+    }
+}
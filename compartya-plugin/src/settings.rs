@@ -0,0 +1,265 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use crate::{last_party::profile_dir, launch_args::DEFAULT_PORT};
+
+const FILE_NAME: &str = "compartya.toml";
+const MOD_NAME: &str = "cat_or_not.ComPartya";
+/// pings keep the connections alive and laminar drops them after 5s of silence,
+/// so even the slowest rate has to get a couple through before that
+const PING_INTERVAL_RANGE: (u64, u64) = (100, 2000);
+
+/// each setting is mirrored by a convar; the name and its help text
+pub const CONVARS: [(&str, &str); 7] = [
+    (
+        "p_port",
+        "the port parties reach you on; changes once you leave the party",
+    ),
+    (
+        "p_ping_interval",
+        "milliseconds between pings to the lobby and its members; 100 to 2000",
+    ),
+    (
+        "p_gui_collapsed",
+        "whether the party window starts collapsed <0/1>",
+    ),
+    (
+        "p_auto_follow",
+        "join the host's server as soon as they give an order <0/1>",
+    ),
+    (
+        "p_fallback_server",
+        "the server to go to when the host hasn't given an order yet; empty for none",
+    ),
+    (
+        "p_matchmaking_servers",
+        "matchmaking servers to try after the launch arg ones: \"<ip:port or host> <more servers>\"",
+    ),
+    ("p_mirror_chat", "show party chat in the game chat too <0/1>"),
+];
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub port: u16,
+    /// milliseconds
+    pub ping_interval: u64,
+    pub gui_collapsed: bool,
+    pub auto_follow: bool,
+    /// a server id, empty for none
+    pub fallback_server: String,
+    pub matchmaking_servers: Vec<String>,
+    pub mirror_chat: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            ping_interval: 500,
+            gui_collapsed: true,
+            auto_follow: true,
+            fallback_server: String::new(),
+            matchmaking_servers: Vec::new(),
+            mirror_chat: true,
+        }
+    }
+}
+
+impl Settings {
+    pub fn ping_interval(&self) -> Duration {
+        let (min, max) = PING_INTERVAL_RANGE;
+        Duration::from_millis(self.ping_interval.clamp(min, max))
+    }
+
+    pub fn fallback_server(&self) -> Option<&str> {
+        Some(self.fallback_server.as_str()).filter(|id| !id.is_empty())
+    }
+
+    /// the value of the convar with this name
+    pub fn get(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "p_port" => self.port.to_string(),
+            "p_ping_interval" => self.ping_interval.to_string(),
+            "p_gui_collapsed" => (self.gui_collapsed as u8).to_string(),
+            "p_auto_follow" => (self.auto_follow as u8).to_string(),
+            "p_fallback_server" => self.fallback_server.clone(),
+            "p_matchmaking_servers" => self.matchmaking_servers.join(" "),
+            "p_mirror_chat" => (self.mirror_chat as u8).to_string(),
+            _ => return None,
+        })
+    }
+
+    /// false if the convar isn't ours or the value doesn't fit
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        let value = value.trim();
+        let flag = match value {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        };
+
+        match (name, flag) {
+            ("p_port", _) => value.parse().map(|port| self.port = port).is_ok(),
+            ("p_ping_interval", _) => value
+                .parse()
+                .map(|interval| self.ping_interval = interval)
+                .is_ok(),
+            ("p_gui_collapsed", Some(flag)) => {
+                self.gui_collapsed = flag;
+                true
+            }
+            ("p_auto_follow", Some(flag)) => {
+                self.auto_follow = flag;
+                true
+            }
+            ("p_mirror_chat", Some(flag)) => {
+                self.mirror_chat = flag;
+                true
+            }
+            ("p_fallback_server", _) if !value.contains(char::is_whitespace) => {
+                self.fallback_server = value.to_string();
+                true
+            }
+            ("p_matchmaking_servers", _) => {
+                self.matchmaking_servers = value
+                    .split([' ', ','])
+                    .filter(|server| !server.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// next to mod.json, whether the mod was installed by hand or as a package
+pub fn settings_path() -> PathBuf {
+    let profile = profile_dir();
+    let packages = fs::read_dir(profile.join("packages"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|package| package.path().join("mods").join(MOD_NAME));
+
+    std::iter::once(profile.join("mods").join(MOD_NAME))
+        .chain(packages)
+        .find(|dir| dir.is_dir())
+        .unwrap_or(profile)
+        .join(FILE_NAME)
+}
+
+pub fn load() -> Settings {
+    let path = settings_path();
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Settings::default();
+    };
+
+    toml::from_str(&contents).unwrap_or_else(|err| {
+        log::warn!("couldn't read {}: {err}", path.display());
+        Settings::default()
+    })
+}
+
+pub fn save(settings: &Settings) {
+    let path = settings_path();
+
+    match toml::to_string(settings) {
+        Ok(contents) => {
+            if let Err(err) = fs::write(&path, contents) {
+                log::warn!("couldn't save the settings to {}: {err}", path.display());
+            }
+        }
+        Err(err) => log::warn!("couldn't serialize the settings: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed() -> Settings {
+        Settings {
+            port: 1234,
+            ping_interval: 250,
+            gui_collapsed: false,
+            auto_follow: false,
+            fallback_server: "server-id".to_string(),
+            matchmaking_servers: vec!["10.0.0.1:2000".to_string(), "example.com".to_string()],
+            mirror_chat: false,
+        }
+    }
+
+    #[test]
+    fn ping_interval_is_clamped() {
+        let interval = |ping_interval| {
+            Settings {
+                ping_interval,
+                ..Default::default()
+            }
+            .ping_interval()
+        };
+
+        assert_eq!(interval(0), Duration::from_millis(100));
+        assert_eq!(interval(750), Duration::from_millis(750));
+        assert_eq!(interval(60_000), Duration::from_millis(2000));
+        assert_eq!(
+            Settings::default().ping_interval(),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn every_convar_round_trips() {
+        let changed = changed();
+
+        for (name, _) in CONVARS {
+            let value = changed.get(name).expect(name);
+            let mut settings = Settings::default();
+
+            assert!(settings.set(name, &value), "{name} = {value}");
+            assert_eq!(settings.get(name).as_ref(), Some(&value), "{name}");
+        }
+    }
+
+    #[test]
+    fn bad_values_change_nothing() {
+        let mut settings = changed();
+
+        assert!(!settings.set("p_port", "99999"));
+        assert!(!settings.set("p_ping_interval", "-1"));
+        assert!(!settings.set("p_auto_follow", "maybe"));
+        assert!(!settings.set("p_fallback_server", "two ids"));
+        assert!(!settings.set("p_not_ours", "1"));
+        assert_eq!(settings, changed());
+    }
+
+    #[test]
+    fn set_reads_loose_values() {
+        let mut settings = Settings::default();
+
+        assert!(settings.set("p_gui_collapsed", " false "));
+        assert!(!settings.gui_collapsed);
+        assert!(settings.set("p_matchmaking_servers", "a:1, b  c,"));
+        assert_eq!(settings.matchmaking_servers, ["a:1", "b", "c"]);
+        assert!(settings.set("p_fallback_server", ""));
+        assert_eq!(settings.fallback_server(), None);
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        let settings = toml::from_str::<Settings>("port = 5").unwrap();
+
+        assert_eq!(
+            settings,
+            Settings {
+                port: 5,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            toml::from_str::<Settings>(&toml::to_string(&changed()).unwrap()).unwrap(),
+            changed()
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use compartya_shared::PlayerUid;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    Ping(SocketAddr, Option<PlayerUid>),
+    ConnectTimeout,
+    Reconnect,
+    ForgetClient(PlayerUid),
+    ShareRoster,
+    ReadyCheckTimeout(u32),
+    ModCheckTimeout(u32),
+    SendOrder(PlayerUid),
+    LanAnnounce,
+    NatProbe(u32),
+    NatVerdict(u32),
+    /// listening failed altogether; try the last address again
+    Rebind,
+}
+
+/// deadlines for the networking thread, the loop sleeps until the closest one
+#[derive(Debug, Default)]
+pub struct Timers {
+    pending: Vec<(Instant, Timer)>,
+}
+
+impl Timers {
+    pub fn schedule(&mut self, after: Duration, timer: Timer) {
+        self.pending.push((Instant::now() + after, timer));
+    }
+
+    pub fn cancel(&mut self, filter: impl Fn(&Timer) -> bool) {
+        self.pending.retain(|(_, timer)| !filter(timer));
+    }
+
+    /// drops everything tied to the party, a nat check and getting the socket back keep going
+    pub fn clear(&mut self) {
+        self.pending.retain(|(_, timer)| {
+            matches!(
+                timer,
+                Timer::NatProbe(_) | Timer::NatVerdict(_) | Timer::Rebind
+            )
+        });
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|(deadline, _)| *deadline).min()
+    }
+
+    pub fn take_due(&mut self, now: Instant) -> Vec<Timer> {
+        let mut due = Vec::new();
+        self.pending.retain(|(deadline, timer)| {
+            if *deadline <= now {
+                due.push(*timer);
+                false
+            } else {
+                true
+            }
+        });
+        due
+    }
+}
@@ -1,6 +1,11 @@
-use compartya_shared::{LobbyUid, PacketMessage, PacketResponse, PartyaError, SentPacket};
+use compartya_shared::{
+    LobbyUid, PacketMessage, PacketResponse, PartyaError, SentPacket, TransferToken,
+};
 use laminar::{Config, Packet, Socket, SocketEvent};
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 #[cfg(target_os = "linux")]
 const SERVER_ADDR: &str = "0.0.0.0";
@@ -8,9 +13,27 @@ const SERVER_ADDR: &str = "0.0.0.0";
 #[cfg(not(target_os = "linux"))]
 const SERVER_ADDR: &str = "192.168.0.243";
 
+/// how long a lobby whose host left waits for a member to take it over
+const ORPHAN_GRACE: Duration = Duration::from_secs(60);
+
+/// a second socket on another port so clients can tell how their nat maps ports
+#[derive(Debug)]
+pub struct Probe {
+    send_socket: crossbeam_channel::Sender<Packet>,
+    port: u16,
+}
+
 #[derive(Default, Debug)]
 pub struct Server {
-    lobby_connections: Vec<(LobbyUid, SocketAddr)>,
+    lobby_connections: Vec<Lobby>,
+}
+
+#[derive(Debug)]
+pub struct Lobby {
+    id: LobbyUid,
+    addr: SocketAddr,
+    transfer_token: TransferToken,
+    orphaned_since: Option<Instant>,
 }
 
 #[allow(clippy::result_unit_err)]
@@ -19,30 +42,46 @@ pub fn main() -> Result<(), ()> {
 
     let mut server = Server::default();
 
-    let addr = format!(
-        "{}:{}",
-        std::env::var("SERVER_ADDR").unwrap_or(SERVER_ADDR.to_string()),
-        std::env::var("PORT").unwrap_or("2000".to_string())
-    );
+    let server_addr = std::env::var("SERVER_ADDR").unwrap_or(SERVER_ADDR.to_string());
+    let port: u16 = std::env::var("PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(2000);
+    let probe_port: u16 = std::env::var("PROBE_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(port.wrapping_add(1));
 
-    let mut socket = Socket::bind_with_config(
-        &addr,
-        Config {
-            idle_connection_timeout: Duration::from_secs(5),
-            ..Default::default()
-        },
-    )
-    .map_err(|err| log::error!("failed to setup socket {err}"))?;
-    let (send_socket, recv_socket) = (socket.get_packet_sender(), socket.get_event_receiver());
-    std::thread::spawn(move || socket.start_polling());
-
-    log::info!("got a socket connection {addr}");
+    let (send_socket, recv_socket) = bind(&format!("{server_addr}:{port}"))?;
+    let (send_probe, recv_probe) = bind(&format!("{server_addr}:{probe_port}"))?;
+    let probe = Probe {
+        send_socket: send_probe,
+        port: probe_port,
+    };
 
     loop {
-        let Ok(event) = recv_socket.recv() else {
+        let event = crossbeam_channel::select! {
+            recv(recv_socket) -> event => event,
+            recv(recv_probe) -> event => {
+                if let Ok(SocketEvent::Packet(packet)) = event {
+                    if let Err(err) = answer_probe(&packet, &probe) {
+                        log::error!("{err}");
+                    }
+                }
+                continue;
+            }
+        };
+        let Ok(event) = event else {
             continue;
         };
 
+        server.lobby_connections.retain(|lobby| {
+            lobby
+                .orphaned_since
+                .map(|since| since.elapsed() < ORPHAN_GRACE)
+                .unwrap_or(true)
+        });
+
         match event {
             SocketEvent::Packet(packet) => {
                 let recv_packet: SentPacket = match packet.payload().try_into() {
@@ -56,7 +95,7 @@ pub fn main() -> Result<(), ()> {
 
                 let maybe_err = match recv_packet {
                     SentPacket::PacketMessage(msg) => {
-                        process_message(addr, msg, &send_socket, &mut server)
+                        process_message(addr, msg, &send_socket, &probe, &mut server)
                     }
                     SentPacket::PacketResponse(response) => {
                         process_response(addr, response, &send_socket, &mut server)
@@ -73,20 +112,66 @@ pub fn main() -> Result<(), ()> {
             SocketEvent::Connect(addr) => log::info!("{} connected", addr),
             SocketEvent::Timeout(_) => {}
             SocketEvent::Disconnect(addr) => {
-                remove_from_server(&mut server, &addr);
+                orphan_lobby(&mut server, &addr);
                 log::info!("{} disconnected", addr)
             }
         }
     }
 }
 
+fn bind(
+    addr: &str,
+) -> Result<
+    (
+        crossbeam_channel::Sender<Packet>,
+        crossbeam_channel::Receiver<SocketEvent>,
+    ),
+    (),
+> {
+    let mut socket = Socket::bind_with_config(
+        addr,
+        Config {
+            idle_connection_timeout: Duration::from_secs(5),
+            ..Default::default()
+        },
+    )
+    .map_err(|err| log::error!("failed to setup socket {err}"))?;
+    let channels = (socket.get_packet_sender(), socket.get_event_receiver());
+    std::thread::spawn(move || socket.start_polling());
+
+    log::info!("got a socket connection {addr}");
+
+    Ok(channels)
+}
+
+/// the probe port only ever tells clients how it sees them
+fn answer_probe(packet: &Packet, probe: &Probe) -> Result<(), PartyaError> {
+    let payload: Result<SentPacket, _> = packet.payload().try_into();
+    let Ok(SentPacket::PacketMessage(PacketMessage::NatProbe(id, _))) = payload else {
+        return Ok(());
+    };
+
+    _ = probe.send_socket.send(Packet::unreliable(
+        packet.addr(),
+        PacketResponse::NatMapping(id, packet.addr(), probe.port)
+            .send()
+            .try_into()?,
+    ));
+
+    Ok(())
+}
+
 fn process_message(
     addr: SocketAddr,
     msg: PacketMessage,
     send_socket: &crossbeam_channel::Sender<Packet>,
+    probe: &Probe,
     server: &mut Server,
 ) -> Result<(), PartyaError> {
-    let lobby = server.lobby_connections.iter().find(|(_, a)| *a == addr);
+    let lobby = server
+        .lobby_connections
+        .iter()
+        .find(|lobby| lobby.addr == addr && lobby.orphaned_since.is_none());
 
     match (msg, lobby) {
         (PacketMessage::FindLobby(lobby_id), None) => {
@@ -95,7 +180,7 @@ fn process_message(
             let Some(lobby) = server
                 .lobby_connections
                 .iter()
-                .find(|(id, _)| !id.iter().cloned().zip(lobby_id).any(|(c1, c2)| c1 != c2))
+                .find(|lobby| lobby.id == lobby_id)
             else {
                 log::error!(
                     "didn't find lobby {} for {addr}",
@@ -109,16 +194,26 @@ fn process_message(
                 return Ok(());
             };
 
+            if lobby.orphaned_since.is_some() {
+                log::info!("lobby for {addr} lost its host");
+
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::LobbyOrphaned(lobby_id).send().try_into()?,
+                ));
+                return Ok(());
+            }
+
             log::info!("found lobby for {addr}");
 
             _ = send_socket.send(Packet::reliable_unordered(
-                lobby.1,
+                lobby.addr,
                 PacketMessage::NewClient(addr).send().try_into()?,
             ));
 
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
-                PacketResponse::FoundLobby(lobby.1).send().try_into()?,
+                PacketResponse::FoundLobby(lobby.addr).send().try_into()?,
             ));
         }
         (PacketMessage::CreateLobby, None) => {
@@ -133,20 +228,94 @@ fn process_message(
                 id.iter().collect::<String>()
             );
 
-            server.lobby_connections.push((id, addr));
+            let transfer_token = new_transfer_token();
+
+            server.lobby_connections.push(Lobby {
+                id,
+                addr,
+                transfer_token,
+                orphaned_since: None,
+            });
+
+            _ = send_socket.send(Packet::reliable_unordered(
+                addr,
+                PacketResponse::CreatedLobby(id, transfer_token)
+                    .send()
+                    .try_into()?,
+            ))
+        }
+        (PacketMessage::ReclaimLobby(lobby_id, transfer_token), None) => {
+            let Some(lobby) = server.lobby_connections.iter_mut().find(|lobby| {
+                lobby.id == lobby_id
+                    && lobby.transfer_token == transfer_token
+                    && lobby.orphaned_since.is_some()
+            }) else {
+                log::warn!(
+                    "{addr} failed to take over lobby {}",
+                    lobby_id.iter().collect::<String>()
+                );
+
+                _ = send_socket.send(Packet::reliable_unordered(
+                    addr,
+                    PacketResponse::NoLobby(lobby_id).send().try_into()?,
+                ));
+                return Ok(());
+            };
+
+            log::info!(
+                "{addr} took over lobby {}",
+                lobby_id.iter().collect::<String>()
+            );
+
+            lobby.addr = addr;
+            lobby.transfer_token = new_transfer_token();
+            lobby.orphaned_since = None;
 
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
-                PacketResponse::CreatedLobby(id).send().try_into()?,
+                PacketResponse::CreatedLobby(lobby.id, lobby.transfer_token)
+                    .send()
+                    .try_into()?,
             ))
         }
-        (PacketMessage::Ping(_), Some(_)) => {
+        (PacketMessage::RotateTransferToken(lobby_id), Some(_)) => {
+            let Some(lobby) = server
+                .lobby_connections
+                .iter_mut()
+                .find(|lobby| lobby.id == lobby_id && lobby.addr == addr)
+            else {
+                log::warn!("{addr} tried to rotate a transfer token for a lobby it doesn't host");
+                return Ok(());
+            };
+
+            lobby.transfer_token = new_transfer_token();
+
             _ = send_socket.send(Packet::reliable_unordered(
                 addr,
-                PacketResponse::Pong.send().try_into()?,
+                PacketResponse::TransferTokenRotated(lobby.transfer_token)
+                    .send()
+                    .try_into()?,
             ))
         }
-        (PacketMessage::Ping(_), _) => {}
+        // anyone can ping so clients can tell if we are up before picking us
+        (PacketMessage::Ping(_, seq), _) => {
+            _ = send_socket.send(Packet::unreliable(
+                addr,
+                PacketResponse::Pong(seq).send().try_into()?,
+            ))
+        }
+        (PacketMessage::NatProbe(id, other_port), _) => {
+            let mapping: Vec<u8> = PacketResponse::NatMapping(id, addr, probe.port)
+                .send()
+                .try_into()?;
+
+            if other_port {
+                _ = probe
+                    .send_socket
+                    .send(Packet::unreliable(addr, mapping.clone()));
+            }
+            _ = send_socket.send(Packet::unreliable(addr, mapping));
+        }
         (m, _) => Err(PartyaError::IllegalPacket(Box::new(m.send())))?,
     }
 
@@ -160,7 +329,7 @@ fn process_response(
     _server: &mut Server,
 ) -> Result<(), PartyaError> {
     match response {
-        PacketResponse::Pong => {
+        PacketResponse::Pong(_) => {
             // _ = send_socket.send(Packet::unreliable(
             //     addr,
             //     PacketMessage::Ping(None).send().try_into()?,
@@ -173,7 +342,29 @@ fn process_response(
 }
 
 fn remove_from_server(server: &mut Server, addr: &SocketAddr) {
-    if let Some(i) = server.lobby_connections.iter().position(|(_, a)| a == addr) {
+    if let Some(i) = server
+        .lobby_connections
+        .iter()
+        .position(|lobby| lobby.addr == *addr)
+    {
         _ = server.lobby_connections.swap_remove(i)
     }
 }
+
+fn orphan_lobby(server: &mut Server, addr: &SocketAddr) {
+    if let Some(lobby) = server
+        .lobby_connections
+        .iter_mut()
+        .find(|lobby| lobby.addr == *addr && lobby.orphaned_since.is_none())
+    {
+        lobby.orphaned_since = Some(Instant::now());
+    }
+}
+
+fn new_transfer_token() -> TransferToken {
+    nanoid::nanoid!(16)
+        .chars()
+        .collect::<Vec<char>>()
+        .try_into()
+        .expect("can't fail to collect a 16 len vec into a 16 len array")
+}
@@ -0,0 +1,194 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use x25519_dalek::StaticSecret;
+
+use crate::{LobbyUid, Password, ResumeToken, ResumeVerifier};
+
+pub type PublicKey = [u8; 32];
+
+/// a x25519 key pair; one per lobby for the host and one per session for members
+#[derive(Clone)]
+pub struct KeyPair {
+    secret: StaticSecret,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        x25519_dalek::PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// `secret` is something only the two ends should know so a relay in the middle can't swap the keys
+    pub fn session_key(&self, theirs: PublicKey, secret: &[char]) -> SessionKey {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(theirs));
+        let ours = self.public();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"compartya session key");
+        hasher.update(shared.as_bytes());
+        hasher.update(secret.iter().collect::<String>().as_bytes());
+        // both ends have to hash the keys in the same order
+        hasher.update(ours.min(theirs));
+        hasher.update(ours.max(theirs));
+
+        SessionKey(hasher.finalize().into())
+    }
+}
+
+impl Default for KeyPair {
+    fn default() -> Self {
+        Self::generate()
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &self.public())
+            .finish_non_exhaustive()
+    }
+}
+
+/// shared between the host and a single member
+#[derive(Clone)]
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+    pub fn seal(&self, plaintext: &str) -> Option<Sealed> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.0));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        Some(Sealed {
+            nonce: nonce.into(),
+            ciphertext: cipher.encrypt(&nonce, plaintext.as_bytes()).ok()?,
+        })
+    }
+
+    pub fn open(&self, sealed: &Sealed) -> Option<String> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.0));
+
+        String::from_utf8(
+            cipher
+                .decrypt(
+                    Nonce::from_slice(&sealed.nonce),
+                    sealed.ciphertext.as_slice(),
+                )
+                .ok()?,
+        )
+        .ok()
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Sealed {
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+/// lets a member recognize a party they were in without keeping its password around
+pub fn password_verifier(lobby_id: &LobbyUid, password: &Password) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"compartya password verifier");
+    hasher.update(lobby_id.iter().collect::<String>().as_bytes());
+    hasher.update(password.iter().collect::<String>().as_bytes());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// the host keeps this instead of the token so the roster can carry it
+pub fn resume_verifier(resume_token: &ResumeToken) -> ResumeVerifier {
+    let mut hasher = Sha256::new();
+    hasher.update(b"compartya resume verifier");
+    hasher.update(resume_token.iter().collect::<String>().as_bytes());
+
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: Password = ['h', 'u', 'n', 't', 'e', 'r', '2', ' '];
+
+    fn session_keys(host_secret: &[char], member_secret: &[char]) -> (SessionKey, SessionKey) {
+        let host = KeyPair::generate();
+        let member = KeyPair::generate();
+
+        (
+            host.session_key(member.public(), host_secret),
+            member.session_key(host.public(), member_secret),
+        )
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let (host, member) = session_keys(&PASSWORD, &PASSWORD);
+
+        let sealed = host.seal("server password").unwrap();
+        assert_eq!(member.open(&sealed).as_deref(), Some("server password"));
+
+        let sealed = member.seal("").unwrap();
+        assert_eq!(host.open(&sealed).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn different_secrets_dont_open() {
+        let mut other = PASSWORD;
+        other[0] = 'x';
+        let (host, member) = session_keys(&PASSWORD, &other);
+
+        assert_eq!(member.open(&host.seal("server password").unwrap()), None);
+    }
+
+    #[test]
+    fn other_sessions_dont_open() {
+        let (host, _) = session_keys(&PASSWORD, &PASSWORD);
+        let (_, member) = session_keys(&PASSWORD, &PASSWORD);
+
+        assert_eq!(member.open(&host.seal("server password").unwrap()), None);
+    }
+
+    #[test]
+    fn tampering_doesnt_open() {
+        let (host, member) = session_keys(&PASSWORD, &PASSWORD);
+
+        let mut sealed = host.seal("server password").unwrap();
+        sealed.ciphertext[0] ^= 1;
+        assert_eq!(member.open(&sealed), None);
+
+        let mut sealed = host.seal("server password").unwrap();
+        sealed.nonce[0] ^= 1;
+        assert_eq!(member.open(&sealed), None);
+    }
+
+    #[test]
+    fn resume_verifier_is_stable() {
+        let token = ['a'; 16];
+        let mut other = token;
+        other[15] = 'b';
+
+        assert_eq!(resume_verifier(&token), resume_verifier(&token));
+        assert_ne!(resume_verifier(&token), resume_verifier(&other));
+    }
+}
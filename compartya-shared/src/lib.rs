@@ -1,16 +1,255 @@
-use std::net::SocketAddr;
+use crypto::{PublicKey, Sealed};
+use std::{fmt, net::SocketAddr};
 use thiserror::Error;
 
+pub mod crypto;
+
 pub type LobbyUid = [char; 8];
 pub type Password = [char; 8];
 pub type PlayerUid = [char; 5];
+pub type ResumeToken = [char; 16];
+pub type ResumeVerifier = [u8; 32];
+pub type TransferToken = [char; 16];
+
+pub const MAX_CHAT_LEN: usize = 256;
+pub const MAX_NAME_LEN: usize = 32;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
 pub enum Order {
-    JoinServer(String, String),
+    JoinServer {
+        id: String,
+        password: String,
+        required_mods: Vec<RequiredMod>,
+        /// free slots left after the host joined, if we know them
+        free_slots: Option<u32>,
+    },
 
-    #[default]
     LeaveServer,
+
+    /// the host hasn't given any order yet
+    #[default]
+    NoOrder,
+}
+
+impl Order {
+    pub fn join_server(id: impl Into<String>) -> Self {
+        Order::JoinServer {
+            id: id.into(),
+            password: String::new(),
+            required_mods: Vec::new(),
+            free_slots: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct RequiredMod {
+    pub name: String,
+    pub version: String,
+}
+
+/// how far a member got with the last order
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub enum OrderStatus {
+    WaitingForSlot,
+    Received,
+    SearchingServers,
+    ServerNotFound,
+    MissingMods(String),
+    AuthFailed(String),
+    Connecting,
+    Connected,
+}
+
+impl OrderStatus {
+    /// nothing else will be reported for this order after it
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::ServerNotFound
+                | OrderStatus::MissingMods(_)
+                | OrderStatus::AuthFailed(_)
+                | OrderStatus::Connected
+        )
+    }
+
+    pub fn sanitized(self) -> Self {
+        let clean = |detail: String| {
+            detail
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_CHAT_LEN)
+                .collect()
+        };
+
+        match self {
+            OrderStatus::MissingMods(detail) => OrderStatus::MissingMods(clean(detail)),
+            OrderStatus::AuthFailed(detail) => OrderStatus::AuthFailed(clean(detail)),
+            status => status,
+        }
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderStatus::WaitingForSlot => write!(f, "waiting for a free slot"),
+            OrderStatus::Received => write!(f, "received"),
+            OrderStatus::SearchingServers => write!(f, "searching servers"),
+            OrderStatus::ServerNotFound => write!(f, "server not found"),
+            OrderStatus::MissingMods(detail) => write!(f, "missing mods: {detail}"),
+            OrderStatus::AuthFailed(detail) => write!(f, "auth failed: {detail}"),
+            OrderStatus::Connecting => write!(f, "connecting"),
+            OrderStatus::Connected => write!(f, "connected"),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+pub struct Roster {
+    /// where the lobby is registered; both change when the host has to register it again
+    pub lobby_id: Option<LobbyUid>,
+    pub matchmaking: Option<SocketAddr>,
+    /// only the successor gets it so nobody else can take the lobby over
+    pub transfer_token: Option<TransferToken>,
+    pub members: Vec<RosterMember>,
+    pub settings: LobbySettings,
+}
+
+impl Roster {
+    /// every member has the same roster so they all pick the same successor
+    pub fn successor(&self) -> Option<PlayerUid> {
+        self.members
+            .iter()
+            .filter(|member| !member.suspended)
+            .map(|member| member.uid)
+            .min()
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct RosterMember {
+    pub uid: PlayerUid,
+    /// lets the successor check a resume without being able to make one
+    pub resume_verifier: ResumeVerifier,
+    pub info: MemberInfo,
+    /// reconnecting members can't take over since they won't hear the host is gone
+    pub suspended: bool,
+}
+
+/// what the host set up for the lobby; the successor keeps it
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+pub struct LobbySettings {
+    /// members that joined with the invite don't know the password otherwise
+    pub password: Password,
+    pub invite: Option<Password>,
+    /// counts the host too
+    pub max_size: u8,
+    pub locked: bool,
+    pub bans: Vec<Ban>,
+    pub join_priority: Vec<PlayerUid>,
+}
+
+/// only lasts as long as the lobby; names can be changed so it goes by uid and ip
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Ban {
+    pub uid: PlayerUid,
+    pub addr: SocketAddr,
+    pub reason: String,
+}
+
+impl Ban {
+    /// the port doesn't count since a new socket or nat mapping gets another one
+    pub fn covers(&self, uid: Option<PlayerUid>, addr: SocketAddr) -> bool {
+        Some(self.uid) == uid || self.addr.ip() == addr.ip()
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default, PartialEq)]
+pub struct MemberInfo {
+    pub name: String,
+    pub nickname: Option<String>,
+}
+
+impl MemberInfo {
+    pub fn sanitized(&self) -> Self {
+        Self {
+            name: sanitize_name(&self.name).unwrap_or_else(|| "unnamed".to_string()),
+            nickname: self.nickname.as_deref().and_then(sanitize_name),
+        }
+    }
+
+    pub fn display_name(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => format!("{nickname} ({})", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// strips anything that isn't printable and caps the length; `None` if nothing is left
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect::<String>();
+    let name = name.trim();
+
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub enum AuthRejection {
+    Banned(String),
+    Full(u8),
+    Locked,
+    /// too many wrong passwords from the same address
+    Throttled,
+}
+
+impl fmt::Display for AuthRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthRejection::Banned(reason) => write!(f, "banned from the party: {reason}"),
+            AuthRejection::Full(max) => write!(f, "the party is full ({max} players)"),
+            AuthRejection::Locked => write!(f, "the party is locked"),
+            AuthRejection::Throttled => write!(f, "too many wrong passwords, try again later"),
+        }
+    }
+}
+
+/// multicast by hosts of LAN parties so members can find them without the matchmaking server
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct LanAnnouncement {
+    pub lobby_id: LobbyUid,
+    pub host: String,
+    /// counts the host too
+    pub members: u8,
+    pub max_size: u8,
+    pub locked: bool,
+    /// the host's party port; the address comes from the packet
+    pub port: u16,
+}
+
+impl LanAnnouncement {
+    const MAGIC: &'static [u8; 4] = b"CPLA";
+
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let mut packet = Self::MAGIC.to_vec();
+        packet.extend(bincode::serialize(self).ok()?);
+
+        Some(packet)
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        let announcement: Self = bincode::deserialize(packet.strip_prefix(Self::MAGIC)?).ok()?;
+
+        Some(Self {
+            host: sanitize_name(&announcement.host)?,
+            ..announcement
+        })
+    }
 }
 
 #[derive(Error, Debug)]
@@ -39,30 +278,55 @@ pub enum PacketMessage {
     // server
     FindLobby(LobbyUid),
     CreateLobby,
+    ReclaimLobby(LobbyUid, TransferToken),
+    /// the host asks for a new transfer token when someone who might have had it leaves
+    RotateTransferToken(LobbyUid),
     NewClient(SocketAddr),
+    /// asks for the address the server sees; the bool asks for a second answer from the probe port
+    NatProbe(u32, bool),
 
     // plugin
-    Auth(Password),
+    Auth(Password, MemberInfo, PublicKey),
+    Resume(PlayerUid, ResumeToken, PublicKey),
     GetLastOrder(PlayerUid),
-    NewOrder(PlayerUid, Order),
+    /// the server password is taken out of the order and sealed for the member
+    NewOrder(PlayerUid, Order, Option<Sealed>),
+    OrderStatus(PlayerUid, OrderStatus),
+    Preflight(PlayerUid, u32, Vec<RequiredMod>),
+    PreflightResult(PlayerUid, u32, Vec<String>),
+    Roster(PlayerUid, Roster),
+    PartyChat(PlayerUid, String),
+    RelayedChat(PlayerUid, String, String),
+    MemberJoined(PlayerUid, PlayerUid, MemberInfo),
+    MemberLeft(PlayerUid, PlayerUid),
+    ReadyCheck(PlayerUid, u32, u32),
+    ReadyAnswer(PlayerUid, u32, bool),
+    Kicked(PlayerUid, String, bool),
     VibeCheck,
 
     // general
-    Ping(Option<PlayerUid>),
+    Ping(Option<PlayerUid>, u32),
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub enum PacketResponse {
     FoundLobby(SocketAddr),
     NoLobby(LobbyUid),
-    CreatedLobby(LobbyUid),
+    LobbyOrphaned(LobbyUid),
+    CreatedLobby(LobbyUid, TransferToken),
+    TransferTokenRotated(TransferToken),
+    /// the address the server saw and the server's probe port
+    NatMapping(u32, SocketAddr, u16),
 
     // plugin
-    AuthAccepted(PlayerUid, Password),
+    AuthAccepted(PlayerUid, Password, ResumeToken, PublicKey),
     FailedAuth,
+    Resumed(PlayerUid, PublicKey),
+    ResumeRejected,
+    AuthRejected(AuthRejection),
 
     // general
-    Pong,
+    Pong(u32),
 }
 
 impl<'a> TryFrom<&'a [u8]> for SentPacket {